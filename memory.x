@@ -1,7 +1,7 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 4K
-    CONFIG : ORIGIN = 0x101FF000, LENGTH = 4K
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 16K
+    CONFIG : ORIGIN = 0x101FC000, LENGTH = 16K
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
@@ -1,6 +1,45 @@
 #!/bin/bash
 
-echo "Put device in BOOTSEL mode..."
+# The keyboard's USB ids, as set in run_primary
+VENDOR=2e8a
+PRODUCT=000a
+
+# The keyboard's serial port, found by its USB ids so other boards and
+# serial adapters plugged in alongside are left alone
+keyboard_tty() {
+    local tty usb
+    for tty in /sys/class/tty/ttyACM*; do
+        [ -e "$tty" ] || continue
+        usb=$(readlink -f "$tty/device/..")
+        if [ "$(cat "$usb/idVendor" 2>/dev/null)" = "$VENDOR" ] &&
+            [ "$(cat "$usb/idProduct" 2>/dev/null)" = "$PRODUCT" ]; then
+            echo "/dev/${tty##*/}"
+            return
+        fi
+    done
+
+    # macOS lists the ids in decimal
+    ioreg -r -c IOUSBHostDevice -l 2>/dev/null | awk \
+        -v vendor=$((16#$VENDOR)) -v product=$((16#$PRODUCT)) '
+        /<class IOUSBHostDevice/ { v = ""; p = "" }
+        /"idVendor" = / { v = $NF }
+        /"idProduct" = / { p = $NF }
+        /"IODialinDevice" = / && v == vendor && p == product {
+            gsub(/"/, "", $NF)
+            print $NF
+            exit
+        }'
+}
+
+# A running keyboard reboots into the bootloader when its serial port is
+# opened at 1200 baud and closed again
+TTY_DEVICE=$(keyboard_tty)
+if [ -n "$TTY_DEVICE" ]; then
+    echo "Asking $TTY_DEVICE to enter the bootloader..."
+    stty -f "$TTY_DEVICE" 1200 2>/dev/null || stty -F "$TTY_DEVICE" 1200 2>/dev/null
+fi
+
+echo "Waiting for BOOTSEL mode (hold BOOTSEL while plugging in if nothing happens)..."
 while ! picotool info -d 2>/dev/null | grep -q "type: *RP2040"; do
     sleep 0.5
     echo -n "."
@@ -0,0 +1,446 @@
+// Runs the keyboard's key handling on the host, from a script of key events
+// to the HID reports and sidechannel output they produce. The firmware's own
+// modules are compiled in unchanged, so chords and hard mode can be worked on
+// without a board, and scripts with their expected output serve as tests.
+//
+//     cargo run -- tests/scripts/chord.txt
+//     cargo run -- --primary right tests/scripts/chord.txt
+//
+// A script has one event per line, `<ms> down <key>` or `<ms> up <key>`,
+// with keys named as the verbose sidechannel prints them, such as L_HOME_3.
+// Events are raw switch edges and go through the firmware's debouncing, so
+// a bouncing switch is several edges close together. Time only moves when
+// the script says so, and debounce deadlines reached on the way are shown
+// as `<ms> settle`. Before the first event, `set <name> <value>` changes a
+// setting as on the console. Blank lines and lines starting with # are
+// skipped.
+//
+// Both halves' keys go straight to the engine unless `--primary` names the
+// half with USB. The other half's keys then queue for the sync wire and
+// reach the primary a message later, shown as `<ms> sync`, as on the board.
+
+// Only the key handling of the shared modules is used here
+#![allow(dead_code)]
+
+#[path = "../../src/chord.rs"]
+mod chord;
+#[path = "../../src/clock.rs"]
+mod clock;
+#[path = "../../src/debounce.rs"]
+mod debounce;
+#[path = "../../src/debounced.rs"]
+mod debounced;
+#[path = "../../src/engine.rs"]
+mod engine;
+#[path = "../../src/hard.rs"]
+mod hard;
+#[path = "../../src/layout.rs"]
+mod layout;
+#[path = "../../src/link.rs"]
+mod link;
+#[path = "../../src/matrix.rs"]
+mod matrix;
+#[path = "../../src/output.rs"]
+mod output;
+#[path = "../../src/position.rs"]
+mod position;
+#[path = "../../src/settings.rs"]
+mod settings;
+
+use clock::Clock;
+use debounced::{Debounced, KeypinEvent};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant};
+use engine::Engine;
+use futures_core::Stream;
+use link::{Merge, Received, SyncMessage};
+use matrix::MatrixEvent;
+use position::{Hand, KeyPosition, Row};
+use settings::Settings;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::process::ExitCode;
+use std::task::{Context, Poll, Waker};
+
+const SERIAL_CHANNEL_CAPACITY: usize = 256;
+
+static SERIAL_CHANNEL: Channel<ThreadModeRawMutex, &'static str, SERIAL_CHANNEL_CAPACITY> =
+    Channel::new();
+
+// The board restarts into its ROM bootloader, which is only reported here
+mod boot {
+    pub fn request() {
+        let _ = crate::SERIAL_CHANNEL.try_send("Entering bootloader\r\n");
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (primary, path) = match &args[..] {
+        [path] => (None, path),
+        [flag, hand, path] if flag == "--primary" && hand == "left" => (Some(Hand::Left), path),
+        [flag, hand, path] if flag == "--primary" && hand == "right" => (Some(Hand::Right), path),
+        _ => {
+            eprintln!("Usage: canary-sim [--primary left|right] <script>");
+            return ExitCode::FAILURE;
+        }
+    };
+    let script = match std::fs::read_to_string(path) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(&script, primary) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err((line, e)) => {
+            eprintln!("{path}:{line}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(script: &str, primary: Option<Hand>) -> Result<(), (usize, &'static str)> {
+    let mut settings = Settings::default();
+    let clock = VirtualClock {
+        now: Cell::new(Instant::MIN),
+        next: Cell::new(None),
+    };
+    let mut board = None;
+    let mut text = Text::default();
+
+    for (i, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        if let ["set", name, value] = words[..] {
+            if board.is_some() {
+                return Err((i + 1, "Settings must come before the first event"));
+            }
+            settings.set_named(name, value).map_err(|e| (i + 1, e))?;
+            continue;
+        }
+
+        let [ms, direction, name] = words[..] else {
+            return Err((i + 1, "Expected <ms> down|up <key> or set <name> <value>"));
+        };
+        let at = Instant::from_millis(ms.parse().map_err(|_| (i + 1, "Invalid time"))?);
+        if at < clock.now.get() {
+            return Err((i + 1, "Time goes backwards"));
+        }
+        let position = parse_position(name).ok_or((i + 1, "Unknown key"))?;
+        let edge = match direction {
+            "down" => KeypinEvent::Down,
+            "up" => KeypinEvent::Up,
+            _ => return Err((i + 1, "Expected down or up")),
+        };
+
+        let board = board.get_or_insert_with(|| Board::new(&settings, &clock, primary));
+        board.advance(at, &mut text);
+        println!("{ms} {direction} {name}");
+        board.edge(position, edge);
+        board.poll(&mut text);
+    }
+
+    if let Some(board) = board.as_mut() {
+        board.advance(Instant::MAX, &mut text);
+    }
+    println!("text {:?}", text.tokens.concat());
+    Ok(())
+}
+
+// Time as far as the script has got. A timer polled before its deadline
+// notes the deadline, so the script can stop there on its way forward.
+struct VirtualClock {
+    now: Cell<Instant>,
+    next: Cell<Option<Instant>>,
+}
+
+struct VirtualTimer<'a> {
+    clock: &'a VirtualClock,
+    at: Instant,
+}
+
+impl<'a> Clock for &'a VirtualClock {
+    type Timer = VirtualTimer<'a>;
+
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+
+    fn at(&self, at: Instant) -> VirtualTimer<'a> {
+        VirtualTimer { clock: self, at }
+    }
+}
+
+impl Future for VirtualTimer<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if self.clock.now.get() >= self.at {
+            return Poll::Ready(());
+        }
+        let next = self
+            .clock
+            .next
+            .get()
+            .map_or(self.at, |next| next.min(self.at));
+        self.clock.next.set(Some(next));
+        Poll::Pending
+    }
+}
+
+// Raw edges from the script, waiting for the debouncer
+#[derive(Default)]
+struct Edges(VecDeque<KeypinEvent>);
+
+impl Stream for Edges {
+    type Item = KeypinEvent;
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<KeypinEvent>> {
+        match self.get_mut().0.pop_front() {
+            Some(edge) => Poll::Ready(Some(edge)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+// The sync wire from the secondary half, carrying one key at a time
+struct Link {
+    primary: Hand,
+    // Keys waiting for the wire, as in the secondary's channel
+    queued: VecDeque<MatrixEvent>,
+    // The message on the wire, when it started and when it is all in
+    sending: Option<(SyncMessage, Instant, Instant)>,
+    merge: Merge,
+}
+
+impl Link {
+    fn new(primary: Hand) -> Self {
+        Self {
+            primary,
+            queued: VecDeque::new(),
+            sending: None,
+            merge: Merge::new(),
+        }
+    }
+
+    fn arrives(&self) -> Option<Instant> {
+        self.sending.map(|(_, _, arrives)| arrives)
+    }
+
+    fn key(&mut self, event: MatrixEvent) {
+        let (MatrixEvent::KeyDown(position, _) | MatrixEvent::KeyUp(position, _)) = event;
+        if position.hand == self.primary {
+            self.merge.local(event);
+        } else {
+            self.queued.push_back(event);
+        }
+    }
+
+    // Start the next queued key if the wire is free, as the secondary does
+    fn send(&mut self, now: Instant) {
+        if self.sending.is_some() {
+            return;
+        }
+        let Some(event) = self.queued.pop_front() else {
+            return;
+        };
+        let message = SyncMessage::new(event, now);
+        let (_, len) = message.to_bytes();
+        let bits = link::BYTE_BITS * len as u64;
+        let arrives = now + Duration::from_ticks(link::BIT_TIME.as_ticks() * bits);
+        self.sending = Some((message, now, arrives));
+        self.merge.received(Received::Started);
+    }
+
+    // The message on the wire is all in by `now`
+    fn arrive(&mut self, now: Instant) -> bool {
+        match self.sending {
+            Some((message, started, arrives)) if arrives <= now => {
+                self.sending = None;
+                self.merge.received(Received::Message(message, started));
+                self.send(now);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// Each key's switch debounced as on a board with a pin per key, feeding
+// the engine
+struct Board<'a> {
+    clock: &'a VirtualClock,
+    debounce: debounce::Debounce,
+    keys: Vec<(KeyPosition, Debounced<Edges, &'a VirtualClock>)>,
+    // Between the halves when one is the primary
+    link: Option<Link>,
+    engine: Engine,
+}
+
+impl<'a> Board<'a> {
+    fn new(settings: &Settings, clock: &'a VirtualClock, primary: Option<Hand>) -> Self {
+        Self {
+            clock,
+            debounce: debounce::Debounce::from_settings(settings),
+            keys: Vec::new(),
+            link: primary.map(Link::new),
+            engine: Engine::new(settings),
+        }
+    }
+
+    fn edge(&mut self, position: KeyPosition, edge: KeypinEvent) {
+        let i = match self.keys.iter().position(|(p, _)| *p == position) {
+            Some(i) => i,
+            None => {
+                let debounce = debounce::for_key(Some(position), self.debounce);
+                let key = Debounced::new(Edges::default(), debounce, self.clock);
+                self.keys.push((position, key));
+                self.keys.len() - 1
+            }
+        };
+        self.keys[i].1.inner.0.push_back(edge);
+    }
+
+    // Move time forward to `to`, stopping at each debounce deadline and
+    // message arrival on the way
+    fn advance(&mut self, to: Instant, text: &mut Text) {
+        loop {
+            let arrives = self.link.as_ref().and_then(Link::arrives);
+            let Some(next) = [self.clock.next.get(), arrives]
+                .into_iter()
+                .flatten()
+                .min()
+                .filter(|&next| next <= to)
+            else {
+                break;
+            };
+            self.clock.now.set(next);
+            if self.link.as_mut().is_some_and(|link| link.arrive(next)) {
+                println!("{} sync", next.as_millis());
+                self.feed(Vec::new(), text);
+            }
+            let events = self.debounced();
+            if !events.is_empty() {
+                println!("{} settle", next.as_millis());
+                self.feed(events, text);
+            }
+        }
+        if to != Instant::MAX {
+            self.clock.now.set(to);
+        }
+    }
+
+    fn poll(&mut self, text: &mut Text) {
+        let events = self.debounced();
+        self.feed(events, text);
+    }
+
+    // What the debouncers report at the current time, oldest first
+    fn debounced(&mut self) -> Vec<MatrixEvent> {
+        self.clock.next.set(None);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut events = Vec::new();
+        for (position, key) in &mut self.keys {
+            while let Poll::Ready(Some((edge, at))) = Pin::new(&mut *key).poll_next(&mut cx) {
+                events.push(MatrixEvent::new(*position, edge == KeypinEvent::Down, at));
+            }
+        }
+        events.sort_by_key(|(MatrixEvent::KeyDown(_, at) | MatrixEvent::KeyUp(_, at))| *at);
+        events
+    }
+
+    // Keys from the other half wait for the wire, and the primary's own for
+    // what is on it
+    fn feed(&mut self, events: Vec<MatrixEvent>, text: &mut Text) {
+        match &mut self.link {
+            None => {
+                for event in events {
+                    self.engine.matrix_event(event);
+                }
+            }
+            Some(link) => {
+                for event in events {
+                    link.key(event);
+                }
+                link.send(self.clock.now.get());
+                while let Some(event) = link.merge.next() {
+                    self.engine.matrix_event(event);
+                }
+            }
+        }
+        drain(text);
+    }
+}
+
+// Print what the last event sent to the host and the sidechannel
+fn drain(text: &mut Text) {
+    while let Ok(report) = output::REPORT_CHANNEL.try_receive() {
+        let keycodes: Vec<String> = report.keycodes.iter().map(|k| format!("{k:02x}")).collect();
+        println!("  report {:02x} {}", report.modifier, keycodes.join(" "));
+        text.report(report.modifier, report.keycodes[0]);
+    }
+
+    let mut serial = String::new();
+    while let Ok(part) = SERIAL_CHANNEL.try_receive() {
+        serial.push_str(part);
+    }
+    for line in serial.lines() {
+        println!("  serial {line}");
+    }
+}
+
+// What the reports would type on a US layout. Keys that type nothing
+// printable, such as modified ones, are shown as <modifier:usage>.
+#[derive(Default)]
+struct Text {
+    tokens: Vec<String>,
+}
+
+impl Text {
+    fn report(&mut self, modifier: u8, keycode: u8) {
+        if keycode == 0 {
+            return;
+        }
+        let typed = (' '..='~')
+            .chain(['\n', '\t'])
+            .find(|&c| output::usage(c) == Some((modifier, keycode)));
+        match typed {
+            Some(c) => self.tokens.push(c.to_string()),
+            None if output::usage('\x08') == Some((modifier, keycode)) => {
+                self.tokens.pop();
+            }
+            None => self.tokens.push(format!("<{modifier:02x}:{keycode:02x}>")),
+        }
+    }
+}
+
+// A key by the name `KeyPosition::send_name` gives it, such as L_TOP_3
+fn parse_position(name: &str) -> Option<KeyPosition> {
+    let mut parts = name.split('_');
+    let hand = match parts.next()? {
+        "L" => Hand::Left,
+        "R" => Hand::Right,
+        _ => return None,
+    };
+    let row = match parts.next()? {
+        "TOP" => Row::Top,
+        "HOME" => Row::Home,
+        "BOTTOM" => Row::Bottom,
+        "THUMB" => Row::Thumb,
+        _ => return None,
+    };
+    let col: u8 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || col < 1 || col > position::cols(row) {
+        return None;
+    }
+    Some(KeyPosition::new(hand, row, col))
+}
@@ -0,0 +1,129 @@
+// The debouncer fed random raw edges, as a bouncing or chattering switch
+// gives it, checked for what the engine relies on whatever the timing: reports
+// alternate down and up, and once the pin is left alone the last one matches
+// the pin.
+
+// Only the debouncer is used here; the rest comes in with its settings
+#![allow(dead_code)]
+
+#[path = "../../src/chord.rs"]
+mod chord;
+#[path = "../../src/clock.rs"]
+mod clock;
+#[path = "../../src/debounce.rs"]
+mod debounce;
+#[path = "../../src/hard.rs"]
+mod hard;
+#[path = "../../src/layout.rs"]
+mod layout;
+#[path = "../../src/matrix.rs"]
+mod matrix;
+#[path = "../../src/output.rs"]
+mod output;
+#[path = "../../src/position.rs"]
+mod position;
+#[path = "../../src/settings.rs"]
+mod settings;
+
+use debounce::{Algorithm, Debounce, Debouncer};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant};
+
+static SERIAL_CHANNEL: Channel<ThreadModeRawMutex, &'static str, 16> = Channel::new();
+
+const RUNS: usize = 2000;
+const EDGES: usize = 200;
+
+struct Rng(u64);
+
+impl Rng {
+    fn random(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.random() % n
+    }
+}
+
+struct Run {
+    debouncer: Debouncer,
+    // Level last reported, starting up
+    reported: bool,
+}
+
+impl Run {
+    fn check(&mut self, report: Option<bool>, raw: bool) {
+        let Some(down) = report else {
+            return;
+        };
+        assert_ne!(down, self.reported, "Reported the same level twice");
+        assert_eq!(down, raw, "Reported a level the pin is not at");
+        self.reported = down;
+    }
+
+    // Settle at every deadline up to `until`, as the scanner does while it
+    // waits for the next edge
+    fn settle(&mut self, until: Option<Instant>, raw: bool) {
+        while let Some(deadline) = self.debouncer.deadline() {
+            if until.is_some_and(|until| deadline > until) {
+                break;
+            }
+            let report = self.debouncer.settle(deadline);
+            assert!(report.is_some(), "Deadline passed without a report");
+            self.check(report, raw);
+        }
+    }
+}
+
+fn random_debounce(rng: &mut Rng) -> Debounce {
+    let algorithm = match rng.below(3) {
+        0 => Algorithm::Eager,
+        1 => Algorithm::Deferred,
+        _ => Algorithm::Asymmetric,
+    };
+    Debounce {
+        algorithm,
+        press: Duration::from_millis(1 + rng.below(30)),
+        release: Duration::from_millis(1 + rng.below(30)),
+    }
+}
+
+#[test]
+fn reports_alternate_and_end_on_the_raw_level() {
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..RUNS {
+        let mut run = Run {
+            debouncer: Debouncer::new(random_debounce(&mut rng)),
+            reported: false,
+        };
+        let mut now = Instant::from_millis(1000);
+        let mut raw = false;
+        for _ in 0..EDGES {
+            // Mostly bounces well inside the debounce times, with some long
+            // enough for an edge to settle
+            now += match rng.below(4) {
+                0 => Duration::from_millis(20 + rng.below(60)),
+                _ => Duration::from_micros(rng.below(5000)),
+            };
+            run.settle(Some(now), raw);
+
+            // Settling before the deadline does nothing
+            assert!(run.debouncer.settle(now).is_none(), "Reported early");
+
+            // Edges usually alternate, but one can be missed between scans
+            if rng.below(8) != 0 {
+                raw = !raw;
+            }
+            let report = run.debouncer.edge(raw, now);
+            run.check(report, raw);
+        }
+
+        run.settle(None, raw);
+        assert_eq!(run.reported, raw, "Left at a level the pin is not at");
+    }
+}
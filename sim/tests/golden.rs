@@ -0,0 +1,49 @@
+// Runs every script in tests/scripts through the simulator and compares what
+// it prints with the .out file beside the script. BLESS=1 rewrites the .out
+// files from the current output instead, for reviewing as a diff.
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn scripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let bless = std::env::var_os("BLESS").is_some();
+
+    let mut scripts: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty(), "No scripts in {}", dir.display());
+
+    let mut failed = Vec::new();
+    for script in &scripts {
+        let run = Command::new(env!("CARGO_BIN_EXE_canary-sim"))
+            .arg(script)
+            .output()
+            .unwrap();
+        assert!(
+            run.status.success(),
+            "{}: {}",
+            script.display(),
+            String::from_utf8_lossy(&run.stderr)
+        );
+        let actual = String::from_utf8(run.stdout).unwrap();
+
+        let golden = script.with_extension("out");
+        if bless {
+            std::fs::write(&golden, &actual).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&golden).unwrap_or_default();
+        if actual != expected {
+            failed.push(golden.display().to_string());
+        }
+    }
+    assert!(
+        failed.is_empty(),
+        "Output differs from {}; run with BLESS=1 and review the diff",
+        failed.join(", ")
+    );
+}
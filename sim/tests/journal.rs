@@ -0,0 +1,195 @@
+// The stash's journal against a simulated flash that can lose power part way
+// through programming or erasing, as it does when the keyboard is unplugged
+// during a save.
+
+// Only the journal's loading and saving are used here
+#![allow(dead_code)]
+
+#[path = "../../src/journal.rs"]
+mod journal;
+
+use embassy_futures::block_on;
+use journal::{Journal, MAX_PAYLOAD, SECTOR_SIZE, SECTORS, Storage};
+
+const SIZE: usize = (SECTORS * SECTOR_SIZE) as usize;
+const POWER_LOST: &str = "Power lost";
+// The RP2040's DMA flash reads move whole words
+const READ_SIZE: usize = 4;
+
+struct Flash {
+    bytes: Vec<u8>,
+    // Bytes left to program or erase before the power goes
+    budget: Option<usize>,
+    erases: [u32; SECTORS as usize],
+    rng: u64,
+}
+
+impl Flash {
+    fn new() -> Self {
+        Self {
+            bytes: vec![0xFF; SIZE],
+            budget: None,
+            erases: [0; SECTORS as usize],
+            rng: 0x2545_F491_4F6C_DD1D,
+        }
+    }
+
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn spend(&mut self) -> bool {
+        match &mut self.budget {
+            Some(0) => false,
+            Some(left) => {
+                *left -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+impl Storage for Flash {
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), &'static str> {
+        let offset = offset as usize;
+        if !offset.is_multiple_of(READ_SIZE) || !bytes.len().is_multiple_of(READ_SIZE) {
+            return Err("Unaligned read");
+        }
+        bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), &'static str> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            let at = offset as usize + i;
+            if !self.spend() {
+                // The byte being programmed has only some of its bits cleared
+                let torn = byte | self.random() as u8;
+                self.bytes[at] &= torn;
+                return Err(POWER_LOST);
+            }
+            self.bytes[at] &= byte;
+        }
+        Ok(())
+    }
+
+    async fn erase(&mut self, sector: u32) -> Result<(), &'static str> {
+        self.erases[sector as usize] += 1;
+        let base = (sector * SECTOR_SIZE) as usize;
+        for i in 0..SECTOR_SIZE as usize {
+            if !self.spend() {
+                // The rest of the sector is left half erased
+                for at in base + i..base + SECTOR_SIZE as usize {
+                    self.bytes[at] |= self.random() as u8;
+                }
+                return Err(POWER_LOST);
+            }
+            self.bytes[base + i] = 0xFF;
+        }
+        Ok(())
+    }
+}
+
+// A payload of varying length that starts with its number
+fn payload(n: u32) -> Vec<u8> {
+    let mut bytes = n.to_le_bytes().to_vec();
+    bytes.resize(4 + (n as usize * 7) % (MAX_PAYLOAD - 4), n as u8);
+    bytes
+}
+
+fn loaded(journal: &mut Journal<Flash>) -> Option<u32> {
+    let entry = block_on(journal.load()).unwrap()?;
+    Some(u32::from_le_bytes(entry.payload()[..4].try_into().unwrap()))
+}
+
+#[test]
+fn empty() {
+    let mut journal = Journal::new(Flash::new());
+    assert_eq!(loaded(&mut journal), None);
+}
+
+#[test]
+fn wear_levels() {
+    let mut journal = Journal::new(Flash::new());
+    for n in 0..1000 {
+        block_on(journal.append(1, &payload(n))).unwrap();
+        let entry = block_on(journal.load()).unwrap().unwrap();
+        assert_eq!(entry.payload(), payload(n));
+    }
+    let erases = journal.storage().erases;
+    let (least, most) = (erases.iter().min(), erases.iter().max());
+    assert!(
+        most.unwrap() - least.unwrap() <= 1,
+        "Uneven wear {erases:?}"
+    );
+}
+
+#[test]
+fn survives_power_loss() {
+    let mut journal = Journal::new(Flash::new());
+    block_on(journal.append(1, &payload(0))).unwrap();
+    let mut committed = 0;
+
+    for n in 1..3000 {
+        let budget = (journal.storage().random() % 3000) as usize;
+        journal.storage().budget = Some(budget);
+        let saved = block_on(journal.append(1, &payload(n)));
+        journal.storage().budget = None;
+
+        // A restart forgets the cached head and scans the flash again
+        let flash = std::mem::replace(journal.storage(), Flash::new());
+        journal = Journal::new(flash);
+
+        let found = loaded(&mut journal).expect("Lost every record");
+        match saved {
+            Ok(()) => assert_eq!(found, n),
+            Err(e) => {
+                assert_eq!(e, POWER_LOST);
+                assert!(
+                    found == committed || found == n,
+                    "{found} after {committed}"
+                );
+            }
+        }
+        committed = found;
+    }
+}
+
+#[test]
+fn torn_header_with_erased_length() {
+    let mut journal = Journal::new(Flash::new());
+    block_on(journal.append(1, &payload(1))).unwrap();
+
+    // Power went after the magic of the next record was programmed, leaving
+    // its length erased
+    let next = journal
+        .storage()
+        .bytes
+        .iter()
+        .rposition(|&b| b != 0xFF)
+        .unwrap()
+        + 1;
+    let slot = next.next_multiple_of(64);
+    journal.storage().bytes[slot..slot + 4].copy_from_slice(&0x1113_C0F7u32.to_le_bytes());
+
+    let mut journal = Journal::new(std::mem::replace(journal.storage(), Flash::new()));
+    assert_eq!(loaded(&mut journal), Some(1));
+    block_on(journal.append(1, &payload(2))).unwrap();
+    assert_eq!(loaded(&mut journal), Some(2));
+}
+
+#[test]
+fn saves_after_prepare_do_not_erase() {
+    let mut journal = Journal::new(Flash::new());
+    for n in 0..1000 {
+        block_on(journal.prepare()).unwrap();
+        let erases = journal.storage().erases;
+        block_on(journal.append(1, &payload(n))).unwrap();
+        assert_eq!(journal.storage().erases, erases, "Save {n} erased");
+        assert_eq!(loaded(&mut journal), Some(n));
+    }
+}
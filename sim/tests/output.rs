@@ -0,0 +1,56 @@
+// What `Output` queues for the host when the report queue is nearly full.
+// Runs without the test harness, on the main thread the channels need.
+
+// Only typing and undo are used here
+#![allow(dead_code)]
+
+#[path = "../../src/output.rs"]
+mod output;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::Channel;
+use output::{DEFAULT_UNICODE, Output, REPORT_CHANNEL};
+use usbd_hid::descriptor::KeyboardReport;
+
+static SERIAL_CHANNEL: Channel<ThreadModeRawMutex, &'static str, 16> = Channel::new();
+
+fn drain() -> Vec<(u8, u8)> {
+    let mut reports = Vec::new();
+    while let Ok(report) = REPORT_CHANNEL.try_receive() {
+        reports.push((report.modifier, report.keycodes[0]));
+    }
+    reports
+}
+
+fn main() {
+    full_queue_drops_whole_taps();
+}
+
+fn full_queue_drops_whole_taps() {
+    let mut output = Output::new(DEFAULT_UNICODE);
+    output.chord_start();
+    output.text("ab");
+    output.chord_end();
+    drain();
+
+    // Leave room for one report, half a tap
+    let filler = KeyboardReport {
+        modifier: 0,
+        reserved: 0,
+        leds: 0,
+        keycodes: [0; 6],
+    };
+    while REPORT_CHANNEL.free_capacity() > 1 {
+        REPORT_CHANNEL.try_send(filler).unwrap();
+    }
+    output.chord_resume();
+    output.tap('c');
+    output.chord_end();
+    assert_eq!(REPORT_CHANNEL.free_capacity(), 1, "Queued half a tap");
+    assert!(SERIAL_CHANNEL.try_receive().is_ok(), "Drop not reported");
+    drain();
+
+    // Undo erases what reached the host, not the dropped key
+    assert!(output.undo_chord());
+    assert_eq!(drain(), [(0, 0x2a), (0, 0), (0, 0x2a), (0, 0)]);
+}
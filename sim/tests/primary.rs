@@ -0,0 +1,58 @@
+// Runs every script in tests/scripts with the left half as the primary and
+// again with the right, so one half's keys come over the sync wire each time,
+// and checks both type what the script does with no wire between the halves.
+// The wire delays keys but must not reorder or misdate them.
+use std::path::Path;
+use std::process::Command;
+
+// What the engine sent, apart from when: the reports, the sidechannel and
+// the text, each in order. The two are printed after each event separately,
+// so their interleaving depends on when keys arrive.
+fn trace(script: &Path, primary: Option<&str>) -> [Vec<String>; 3] {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_canary-sim"));
+    if let Some(primary) = primary {
+        command.args(["--primary", primary]);
+    }
+    let run = command.arg(script).output().unwrap();
+    assert!(
+        run.status.success(),
+        "{}: {}",
+        script.display(),
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let stdout = String::from_utf8(run.stdout).unwrap();
+    ["  report ", "  serial ", "text "].map(|prefix| {
+        stdout
+            .lines()
+            .filter(|line| line.starts_with(prefix))
+            .map(str::to_string)
+            .collect()
+    })
+}
+
+#[test]
+fn either_half_as_primary() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut scripts: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty(), "No scripts in {}", dir.display());
+
+    let mut failed = Vec::new();
+    for script in &scripts {
+        let together = trace(script, None);
+        for primary in ["left", "right"] {
+            if trace(script, Some(primary)) != together {
+                failed.push(format!("{} with {primary} primary", script.display()));
+            }
+        }
+    }
+    assert!(
+        failed.is_empty(),
+        "Typed differently over the sync wire: {}",
+        failed.join(", ")
+    );
+}
@@ -0,0 +1,93 @@
+// Scanner for keys wired one per pin that reads every pin at once from the
+// SIO input register on a timer, instead of arming an interrupt per pin.
+// Keys pressed together show up in the same snapshot and share its time.
+use crate::clock::Clock;
+use crate::debounce::{self, Debounce, Debouncer};
+use crate::keypin::Keypin;
+use crate::matrix::{self, MatrixEvent, Sampled, Sampler};
+use crate::position::{self, KeyPosition};
+use core::task::Poll;
+use embassy_time::Instant;
+use futures_core::Stream;
+
+pub struct BankMatrix<const N: usize, C: Clock> {
+    // Held so the pins stay inputs with their pull-ups; only their masks
+    // and positions are used
+    pins: [Keypin; N],
+    debouncers: [Debouncer; N],
+    // The input register as last read, pressed keys reading low
+    snapshot: u32,
+    // Levels reported by the debouncers but not yet returned
+    pending: [Option<bool>; N],
+    sampler: Sampler<C>,
+}
+
+impl<const N: usize, C: Clock> BankMatrix<N, C> {
+    pub fn new(clock: C, debounce: Debounce, pins: [Keypin; N]) -> Self {
+        const {
+            assert!(
+                N == position::KEYS_PER_HAND as usize,
+                "Expected a pin per key"
+            )
+        };
+        Self {
+            debouncers: pins
+                .each_ref()
+                .map(|pin| Debouncer::new(debounce::for_key(Some(pin.position), debounce))),
+            pins,
+            // As if every key were up, so keys held at boot are reported
+            snapshot: !0,
+            pending: [None; N],
+            sampler: Sampler::new(clock),
+        }
+    }
+}
+
+impl<const N: usize, C: Clock> Sampled for BankMatrix<N, C> {
+    type Clock = C;
+
+    fn sampler(&mut self) -> &mut Sampler<C> {
+        &mut self.sampler
+    }
+
+    fn pending(&self) -> impl Iterator<Item = (usize, Instant)> {
+        (0..N)
+            .filter(|&i| self.pending[i].is_some())
+            .map(|i| (i, self.debouncers[i].changed()))
+    }
+
+    fn take(&mut self, slot: usize) -> Option<(KeyPosition, bool)> {
+        let down = self.pending[slot].take() == Some(true);
+        Some((self.pins[slot].position, down))
+    }
+
+    fn update(&mut self, now: Instant) {
+        let snapshot = rp_pac::SIO.gpio_in().read();
+        let changed = snapshot ^ self.snapshot;
+        self.snapshot = snapshot;
+
+        for ((pin, debouncer), pending) in self
+            .pins
+            .iter()
+            .zip(&mut self.debouncers)
+            .zip(&mut self.pending)
+        {
+            *pending = if changed & pin.mask != 0 {
+                debouncer.edge(snapshot & pin.mask == 0, now)
+            } else {
+                debouncer.settle(now)
+            };
+        }
+    }
+}
+
+impl<const N: usize, C: Clock> Stream for BankMatrix<N, C> {
+    type Item = MatrixEvent;
+
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        matrix::poll_sampled(self.get_mut(), cx)
+    }
+}
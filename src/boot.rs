@@ -0,0 +1,23 @@
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+
+// Opening the serial port at this rate and closing it again asks for the
+// bootloader, as on Arduino boards, so flashing needs no button press
+pub const TOUCH_BAUD: u32 = 1200;
+
+// Raised by a chord, the console or a 1200 baud touch
+static REQUEST: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+pub fn request() {
+    REQUEST.signal(());
+}
+
+// Restart into the ROM's USB mass storage bootloader, as if BOOTSEL were
+// held, once requested and the sidechannel has had a moment to flush
+pub async fn run() {
+    REQUEST.wait().await;
+    let _ = crate::SERIAL_CHANNEL.try_send("Entering bootloader\r\n");
+    Timer::after_millis(100).await;
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+}
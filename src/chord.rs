@@ -0,0 +1,302 @@
+use embassy_time::{Duration, Instant};
+
+const MAX_COMBO: usize = 8;
+
+pub const DUP: char = '⨧';
+pub const SHIFT: char = '⇧';
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Tap,
+    Shifted,
+    Held,
+    HeldShifted,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Behavior {
+    Text,
+    // Undo the previous chord's output, or delete the previous word
+    DeleteWord,
+    // Restart into the USB bootloader for flashing
+    Bootloader,
+    // A key with no place of its own on the layout
+    Key(char),
+}
+
+// How shifted variants without their own expansions are typed
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Shift {
+    Capitalize,
+    Disabled,
+}
+
+// One entry of a variant's cycle list. Expansions are followed by a space
+// unless they are exact, such as a URL prefix or a sentence that ends in
+// its own punctuation.
+#[derive(Clone, Copy)]
+pub struct Expansion {
+    pub text: &'static str,
+    pub exact: bool,
+}
+
+impl Expansion {
+    pub const fn word(text: &'static str) -> Self {
+        Self { text, exact: false }
+    }
+
+    pub const fn exact(text: &'static str) -> Self {
+        Self { text, exact: true }
+    }
+}
+
+// Each variant carries its own cycle list: the first entry is typed when the
+// chord fires and ⨧ steps through the rest. Empty shifted lists fall back to
+// the unshifted variant according to `shift`, and an empty `held` list falls
+// back to `tap`.
+pub struct Chord {
+    pub keys: &'static [char],
+    pub behavior: Behavior,
+    pub shift: Shift,
+    // In hard mode, typing this chord's word letter by letter is undone
+    pub hard: bool,
+    pub tap: &'static [Expansion],
+    pub shifted: &'static [Expansion],
+    pub held: &'static [Expansion],
+    pub held_shifted: &'static [Expansion],
+}
+
+impl Chord {
+    const BASE: Chord = Chord {
+        keys: &[],
+        behavior: Behavior::Text,
+        shift: Shift::Capitalize,
+        hard: false,
+        tap: &[],
+        shifted: &[],
+        held: &[],
+        held_shifted: &[],
+    };
+
+    fn custom(&self, variant: Variant) -> &'static [Expansion] {
+        match variant {
+            Variant::Tap => self.tap,
+            Variant::Shifted => self.shifted,
+            Variant::Held => self.held,
+            Variant::HeldShifted => self.held_shifted,
+        }
+    }
+
+    fn unshifted(&self, variant: Variant) -> &'static [Expansion] {
+        match variant {
+            Variant::Tap | Variant::Shifted => self.tap,
+            Variant::Held | Variant::HeldShifted if self.held.is_empty() => self.tap,
+            Variant::Held | Variant::HeldShifted => self.held,
+        }
+    }
+
+    pub fn expansions(&self, variant: Variant) -> &'static [Expansion] {
+        let expansions = self.custom(variant);
+        if expansions.is_empty() {
+            self.unshifted(variant)
+        } else {
+            expansions
+        }
+    }
+
+    pub fn capitalize(&self, variant: Variant) -> bool {
+        matches!(variant, Variant::Shifted | Variant::HeldShifted)
+            && self.custom(variant).is_empty()
+            && self.shift == Shift::Capitalize
+    }
+}
+
+// Keys from either half can be combined, as the secondary forwards its keys
+// to the primary.
+pub const CHORDS: &[Chord] = &[
+    Chord {
+        keys: &[' ', '\x08'],
+        behavior: Behavior::DeleteWord,
+        ..Chord::BASE
+    },
+    // Also shows or erases the missed chord after a hard mode deletion
+    Chord {
+        keys: &[' ', DUP],
+        behavior: Behavior::Key('\t'),
+        ..Chord::BASE
+    },
+    Chord {
+        keys: &['t', 'n', 's'],
+        hard: true,
+        tap: &[
+            Expansion::word("thanks"),
+            Expansion::word("thank you"),
+            Expansion::exact("Thank you very much!"),
+        ],
+        ..Chord::BASE
+    },
+    Chord {
+        keys: &['c', 'n', 'd'],
+        hard: true,
+        tap: &[Expansion::word("consider")],
+        held: &[Expansion::word("considered")],
+        ..Chord::BASE
+    },
+    Chord {
+        keys: &['c', 'h', 'm'],
+        tap: &[Expansion::word("champs-elysees")],
+        shifted: &[Expansion::word("Champs-Élysées")],
+        ..Chord::BASE
+    },
+    Chord {
+        keys: &['h', 't', 'p'],
+        shift: Shift::Disabled,
+        tap: &[Expansion::exact("https://")],
+        ..Chord::BASE
+    },
+    // Awkward on purpose, so it is never hit while typing
+    Chord {
+        keys: &['\n', 'z', DUP],
+        behavior: Behavior::Bootloader,
+        ..Chord::BASE
+    },
+];
+
+#[derive(Clone, Copy)]
+pub struct KeySet {
+    keys: [char; MAX_COMBO],
+    len: usize,
+}
+
+impl KeySet {
+    const fn new() -> Self {
+        Self {
+            keys: ['\0'; MAX_COMBO],
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, key: char) {
+        if self.len < MAX_COMBO && !self.contains(key) {
+            self.keys[self.len] = key;
+            self.len += 1;
+        }
+    }
+
+    fn remove(&mut self, key: char) -> bool {
+        match self.keys[..self.len].iter().position(|&k| k == key) {
+            Some(i) => {
+                self.keys.copy_within(i + 1..self.len, i);
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn contains(&self, key: char) -> bool {
+        self.keys[..self.len].contains(&key)
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn matches(&self, keys: &[char]) -> bool {
+        keys.len() == self.len && keys.iter().all(|&k| self.contains(k))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // Keys in the order they were pressed
+    pub fn iter(&self) -> impl Iterator<Item = char> + '_ {
+        self.keys[..self.len].iter().copied()
+    }
+}
+
+pub enum Resolved {
+    Keys(KeySet),
+    Chord { chord: &'static Chord, held: bool },
+}
+
+// Collects overlapping key presses into a combo and resolves it on the first
+// release, either to a chord or to the individual keys in press order.
+pub struct Chorder {
+    // All keys of a chord must go down within this window of the first one,
+    // otherwise they are treated as ordinary rolled keystrokes
+    chord_term: Duration,
+    // Chords released after being held this long use their held variants
+    tapping_term: Duration,
+    combo: KeySet,
+    combo_start: Option<Instant>,
+    // Keys already resolved as part of a combo that are still held down
+    consumed: KeySet,
+}
+
+impl Chorder {
+    pub fn new(chord_term: Duration, tapping_term: Duration) -> Self {
+        Self {
+            chord_term,
+            tapping_term,
+            combo: KeySet::new(),
+            combo_start: None,
+            consumed: KeySet::new(),
+        }
+    }
+
+    fn take_combo(&mut self) -> KeySet {
+        let combo = self.combo;
+        for key in combo.iter() {
+            self.consumed.insert(key);
+        }
+        self.combo.clear();
+        self.combo_start = None;
+        combo
+    }
+
+    pub fn key_down(&mut self, key: char, now: Instant) -> Option<Resolved> {
+        let expired = self
+            .combo_start
+            .map(|start| now.duration_since(start) > self.chord_term)
+            .unwrap_or(false);
+
+        let flushed = if expired {
+            Some(Resolved::Keys(self.take_combo()))
+        } else {
+            None
+        };
+
+        if self.combo_start.is_none() {
+            self.combo_start = Some(now);
+        }
+        self.combo.insert(key);
+
+        flushed
+    }
+
+    pub fn key_up(&mut self, key: char, now: Instant) -> Option<Resolved> {
+        if self.consumed.remove(key) {
+            return None;
+        }
+        if !self.combo.contains(key) {
+            return None;
+        }
+
+        let held = self
+            .combo_start
+            .map(|start| now.duration_since(start) >= self.tapping_term)
+            .unwrap_or(false);
+        let combo = self.take_combo();
+        self.consumed.remove(key);
+
+        if combo.len() > 1
+            && let Some(chord) = CHORDS.iter().find(|chord| combo.matches(chord.keys))
+        {
+            return Some(Resolved::Chord { chord, held });
+        }
+
+        Some(Resolved::Keys(combo))
+    }
+}
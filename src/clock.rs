@@ -0,0 +1,35 @@
+use core::future::Future;
+use embassy_time::{Duration, Instant, Timer};
+
+// Where timing-dependent code reads the time and waits for it, passed in so
+// the host simulator can move time forward itself and hold a key for exactly
+// 199 ms. The chorder and debouncer are handed the time with each call and
+// need no clock of their own.
+pub trait Clock: Copy + Unpin {
+    type Timer: Future<Output = ()> + Unpin;
+
+    fn now(&self) -> Instant;
+
+    // Ready once the clock has reached `at`
+    fn at(&self, at: Instant) -> Self::Timer;
+
+    fn after(&self, duration: Duration) -> Self::Timer {
+        self.at(self.now() + duration)
+    }
+}
+
+// The embassy time driver, which is the RP2040's timer on the board
+#[derive(Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    type Timer = Timer;
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn at(&self, at: Instant) -> Timer {
+        Timer::at(at)
+    }
+}
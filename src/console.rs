@@ -0,0 +1,165 @@
+use crate::settings::{self, Settings, Value, send_decimal};
+use embassy_time::Instant;
+use portable_atomic::{AtomicU32, Ordering};
+
+const LINE_LEN: usize = 64;
+const MAX_WORDS: usize = 3;
+
+// Printable ASCII, sliced to echo typed characters back
+const PRINTABLE: &str = " !\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz{|}~";
+
+const HELP: &[&str] = &[
+    "help                   List commands\r\n",
+    "get [setting]          Show one setting or all of them\r\n",
+    "set <setting> <value>  Save a setting, applied after reboot\r\n",
+    "info                   Show firmware version and hand\r\n",
+    "reboot                 Restart the keyboard\r\n",
+    "bootloader             Restart into the USB bootloader\r\n",
+    "stats                  Show uptime and event counts\r\n",
+];
+
+// Counts shown by `stats`
+pub static KEY_EVENTS: AtomicU32 = AtomicU32::new(0);
+pub static REPORTS: AtomicU32 = AtomicU32::new(0);
+
+pub fn count(counter: &AtomicU32) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub enum Command<'a> {
+    Help,
+    Get(Option<&'a str>),
+    Set(&'a str, &'a str),
+    Info,
+    Reboot,
+    Bootloader,
+    Stats,
+}
+
+impl<'a> Command<'a> {
+    // A blank line is no command
+    pub fn parse(line: &'a str) -> Result<Option<Self>, &'static str> {
+        let mut words = [""; MAX_WORDS];
+        let mut len = 0;
+        for word in line.split_ascii_whitespace() {
+            if len == MAX_WORDS {
+                return Err("Too many arguments");
+            }
+            words[len] = word;
+            len += 1;
+        }
+
+        Ok(Some(match words[..len] {
+            [] => return Ok(None),
+            ["help"] => Command::Help,
+            ["get"] => Command::Get(None),
+            ["get", setting] => Command::Get(Some(setting)),
+            ["set", setting, value] => Command::Set(setting, value),
+            ["info"] => Command::Info,
+            ["reboot"] => Command::Reboot,
+            ["bootloader"] => Command::Bootloader,
+            ["stats"] => Command::Stats,
+            [
+                "help" | "get" | "set" | "info" | "reboot" | "bootloader" | "stats",
+                ..,
+            ] => {
+                return Err("Wrong arguments, see help");
+            }
+            _ => return Err("Unknown command, see help"),
+        }))
+    }
+}
+
+// A console line, echoed back and editable with backspace as it arrives
+// over any number of packets
+pub struct Line {
+    buf: [u8; LINE_LEN],
+    len: usize,
+    overflow: bool,
+    // Terminals may send \r\n for Enter; the \n must not end a second line
+    after_cr: bool,
+}
+
+impl Line {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; LINE_LEN],
+            len: 0,
+            overflow: false,
+            after_cr: false,
+        }
+    }
+
+    // Returns true once Enter completes the line, which `take` then returns
+    pub fn push(&mut self, byte: u8) -> bool {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match byte {
+            b'\n' if after_cr => {}
+            b'\r' | b'\n' => {
+                let _ = crate::SERIAL_CHANNEL.try_send("\r\n");
+                return true;
+            }
+            0x08 | 0x7f if self.len > 0 => {
+                self.len -= 1;
+                let _ = crate::SERIAL_CHANNEL.try_send("\x08 \x08");
+            }
+            0x20..=0x7e if self.len < LINE_LEN => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                let i = (byte - 0x20) as usize;
+                let _ = crate::SERIAL_CHANNEL.try_send(&PRINTABLE[i..i + 1]);
+            }
+            0x20..=0x7e => self.overflow = true,
+            _ => {}
+        }
+        false
+    }
+
+    // The completed line, leaving the buffer empty for the next one
+    pub fn take(&mut self) -> Result<&str, &'static str> {
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflow) {
+            return Err("Line too long");
+        }
+        // Only printable ASCII is ever stored
+        core::str::from_utf8(&self.buf[..len]).map_err(|_| "Invalid line")
+    }
+}
+
+// Every command ends with exactly one of these, after any other output
+pub fn reply(result: Result<(), &'static str>) {
+    match result {
+        Ok(()) => {
+            let _ = crate::SERIAL_CHANNEL.try_send("OK\r\n");
+        }
+        Err(e) => {
+            let _ = crate::SERIAL_CHANNEL.try_send("ERR ");
+            let _ = crate::SERIAL_CHANNEL.try_send(e);
+            let _ = crate::SERIAL_CHANNEL.try_send("\r\n");
+        }
+    }
+}
+
+pub fn help() {
+    for line in HELP {
+        let _ = crate::SERIAL_CHANNEL.try_send(line);
+    }
+}
+
+pub fn info(settings: &Settings) {
+    let _ = crate::SERIAL_CHANNEL.try_send("Info firmware=");
+    let _ = crate::SERIAL_CHANNEL.try_send(env!("CARGO_PKG_VERSION"));
+    let _ = crate::SERIAL_CHANNEL.try_send("\r\nInfo hand=");
+    settings.get(settings::HAND).send();
+    let _ = crate::SERIAL_CHANNEL.try_send("\r\n");
+}
+
+pub fn stats() {
+    let _ = crate::SERIAL_CHANNEL.try_send("Stat uptime_s=");
+    send_decimal(Instant::now().as_secs() as u32);
+    let _ = crate::SERIAL_CHANNEL.try_send("\r\nStat key_events=");
+    send_decimal(KEY_EVENTS.load(Ordering::Relaxed));
+    let _ = crate::SERIAL_CHANNEL.try_send("\r\nStat reports=");
+    send_decimal(REPORTS.load(Ordering::Relaxed));
+    let _ = crate::SERIAL_CHANNEL.try_send("\r\n");
+}
@@ -1,56 +1,136 @@
-use crate::keypin::KeypinEvent;
-use core::task::Poll;
+use crate::layout;
+use crate::position::KeyPosition;
+use crate::settings::{self, Settings};
 use embassy_time::{Duration, Instant};
-use futures_core::Stream;
 
-const DEBOUNCE_MS: u64 = 15;
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    // Report an edge at once, then ignore the pin for the debounce time
+    Eager,
+    // Report an edge once the pin has held its new level for the debounce
+    // time
+    Deferred,
+    // Eager presses for latency, deferred releases against chatter
+    Asymmetric,
+}
+
+pub const DEFAULT_ALGORITHM: Algorithm = Algorithm::Eager;
 
-pub struct Debounced<S> {
-    pub inner: S,
-    last_event_time: Option<Instant>,
+// The times apply to the edge that goes down or up: how long that edge is
+// locked out for once reported eagerly, or has to hold before it is reported
+// deferred
+#[derive(Clone, Copy)]
+pub struct Debounce {
+    pub algorithm: Algorithm,
+    pub press: Duration,
+    pub release: Duration,
 }
 
-impl<S> Debounced<S>
-where
-    S: Stream<Item = KeypinEvent>,
-{
-    pub fn new(inner: S) -> Self {
+impl Debounce {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let ms = |key| Duration::from_millis(settings.get(key) as u64);
         Self {
-            inner,
-            last_event_time: None,
+            algorithm: settings.get(settings::DEBOUNCE_ALGORITHM),
+            press: ms(settings::DEBOUNCE_PRESS_MS),
+            release: ms(settings::DEBOUNCE_RELEASE_MS),
         }
     }
+
+    fn time(&self, down: bool) -> Duration {
+        if down { self.press } else { self.release }
+    }
+}
+
+// Keys debounced differently from the stash's setting, such as a worn
+// switch that needs longer
+pub const OVERRIDES: &[(char, Debounce)] = &[];
+
+pub fn for_key(position: Option<KeyPosition>, default: Debounce) -> Debounce {
+    let keycode = position.and_then(layout::keycode);
+    OVERRIDES
+        .iter()
+        .find(|(key, _)| Some(*key) == keycode)
+        .map_or(default, |(_, debounce)| *debounce)
 }
 
-impl<S> Stream for Debounced<S>
-where
-    S: Stream<Item = KeypinEvent> + Unpin,
-{
-    type Item = KeypinEvent;
-
-    fn poll_next(
-        mut self: core::pin::Pin<&mut Self>,
-        cx: &mut core::task::Context<'_>,
-    ) -> Poll<Option<Self::Item>> {
-        let inner = core::pin::Pin::new(&mut self.inner);
-        match inner.poll_next(cx) {
-            Poll::Ready(Some(event)) => {
-                let now = Instant::now();
-                let should_emit = self
-                    .last_event_time
-                    .map(|last| now.duration_since(last) >= Duration::from_millis(DEBOUNCE_MS))
-                    .unwrap_or(true);
-
-                if should_emit {
-                    self.last_event_time = Some(now);
-                    Poll::Ready(Some(event))
-                } else {
-                    cx.waker().wake_by_ref();
-                    Poll::Pending
-                }
-            }
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
+// Debounce state of one key, fed its raw edges. It tracks the raw level
+// rather than trusting edges to alternate, and compares it to the reported
+// level once the key has had time to settle, so an edge dropped during the
+// lockout is caught up and every Down gets its Up.
+pub struct Debouncer {
+    debounce: Debounce,
+    // Level last reported
+    reported: bool,
+    // Raw level and when it last changed
+    raw: bool,
+    changed: Instant,
+    // Eager edges are held back until then
+    locked_until: Option<Instant>,
+}
+
+impl Debouncer {
+    pub fn new(debounce: Debounce) -> Self {
+        Self {
+            debounce,
+            reported: false,
+            raw: false,
+            changed: Instant::MIN,
+            locked_until: None,
+        }
+    }
+
+    fn eager(&self, down: bool) -> bool {
+        match self.debounce.algorithm {
+            Algorithm::Eager => true,
+            Algorithm::Deferred => false,
+            Algorithm::Asymmetric => down,
+        }
+    }
+
+    // The raw pin went down or up. Returns the level to report, if any.
+    pub fn edge(&mut self, down: bool, now: Instant) -> Option<bool> {
+        self.raw = down;
+        self.changed = now;
+        if self.eager(down) && self.locked_until.is_none_or(|until| now >= until) {
+            return self.report(now);
+        }
+        None
+    }
+
+    // When the raw level should next be compared with the reported one, if
+    // they differ: once a deferred edge has been stable long enough, or once
+    // the lockout that held back an eager edge is over
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.raw == self.reported {
+            return None;
+        }
+        if self.eager(self.raw) {
+            self.locked_until
+        } else {
+            Some(self.changed + self.debounce.time(self.raw))
+        }
+    }
+
+    // When the raw level last changed, which for a reported level is when
+    // the key physically got there
+    pub fn changed(&self) -> Instant {
+        self.changed
+    }
+
+    // Called at the deadline
+    pub fn settle(&mut self, now: Instant) -> Option<bool> {
+        if self.deadline().is_some_and(|deadline| now >= deadline) {
+            return self.report(now);
+        }
+        None
+    }
+
+    fn report(&mut self, now: Instant) -> Option<bool> {
+        if self.raw == self.reported {
+            return None;
         }
+        self.reported = self.raw;
+        self.locked_until = Some(now + self.debounce.time(self.reported));
+        Some(self.reported)
     }
 }
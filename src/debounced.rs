@@ -0,0 +1,92 @@
+// The edges of a key on its own pin, debounced. Kept apart from the GPIO so
+// the host simulator can feed in edges of its own.
+use crate::clock::Clock;
+use crate::debounce::{Debounce, Debouncer};
+use core::future::Future;
+use core::task::Poll;
+use embassy_time::Instant;
+use futures_core::Stream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeypinEvent {
+    Down,
+    Up,
+}
+
+pub struct Debounced<S, C: Clock> {
+    pub inner: S,
+    debouncer: Debouncer,
+    clock: C,
+    // Armed for the debouncer's deadline
+    timer: Option<(Instant, C::Timer)>,
+}
+
+impl<S, C> Debounced<S, C>
+where
+    S: Stream<Item = KeypinEvent>,
+    C: Clock,
+{
+    pub fn new(inner: S, debounce: Debounce, clock: C) -> Self {
+        Self {
+            inner,
+            debouncer: Debouncer::new(debounce),
+            clock,
+            timer: None,
+        }
+    }
+}
+
+pub fn event(down: bool) -> KeypinEvent {
+    if down {
+        KeypinEvent::Down
+    } else {
+        KeypinEvent::Up
+    }
+}
+
+impl<S, C> Stream for Debounced<S, C>
+where
+    S: Stream<Item = KeypinEvent> + Unpin,
+    C: Clock,
+{
+    // The level and when the pin got there
+    type Item = (KeypinEvent, Instant);
+
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        // Drain edges until the pin is quiet, so its waker stays registered
+        loop {
+            match core::pin::Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(edge)) => {
+                    let down = edge == KeypinEvent::Down;
+                    if let Some(down) = this.debouncer.edge(down, this.clock.now()) {
+                        return Poll::Ready(Some((event(down), this.debouncer.changed())));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => break,
+            }
+        }
+
+        let Some(deadline) = this.debouncer.deadline() else {
+            this.timer = None;
+            return Poll::Pending;
+        };
+        if this.timer.as_ref().is_none_or(|(at, _)| *at != deadline) {
+            this.timer = Some((deadline, this.clock.at(deadline)));
+        }
+        if let Some((_, timer)) = this.timer.as_mut()
+            && core::pin::Pin::new(timer).poll(cx).is_ready()
+        {
+            this.timer = None;
+            if let Some(down) = this.debouncer.settle(this.clock.now()) {
+                return Poll::Ready(Some((event(down), this.debouncer.changed())));
+            }
+        }
+        Poll::Pending
+    }
+}
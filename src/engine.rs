@@ -0,0 +1,396 @@
+use crate::boot;
+use crate::chord::{self, Behavior, Chord, Chorder, Resolved, Variant};
+use crate::hard::{self, Double, Words};
+use crate::layout;
+use crate::matrix::MatrixEvent;
+use crate::output::{self, Output};
+use crate::settings::{self, Settings, Verbosity};
+use embassy_time::{Duration, Instant};
+
+// How long after a hard mode deletion Tab keeps showing or erasing the chord
+const HINT_TIMEOUT_MS: u64 = 5000;
+
+// The most recent chord, remembered so ⨧ can cycle through its expansions
+#[derive(Clone, Copy)]
+struct Cycle {
+    chord: &'static Chord,
+    variant: Variant,
+    index: usize,
+}
+
+// Tab override after a hard mode deletion
+#[derive(Clone, Copy)]
+enum Hint {
+    None,
+    // Tab types the missed chord's keys
+    Armed {
+        chord: &'static Chord,
+        since: Instant,
+    },
+    // The keys are on screen, attributed to the output's chord span so they
+    // can be erased exactly; Tab or space+backspace erases them
+    Shown {
+        since: Instant,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum Last {
+    None,
+    Key(char),
+    Chord(Cycle),
+}
+
+pub struct Engine {
+    chorder: Chorder,
+    output: Output,
+    last: Last,
+    // Oneshot shift from tapping ⇧ or from smart punctuation, applied to the
+    // next letter or chord
+    oneshot_shift: bool,
+    shift_held: bool,
+    // Whether anything was shifted while ⇧ was held, so releasing it does
+    // not also arm the oneshot
+    shift_used: bool,
+    hard_mode: bool,
+    double_letters: Double,
+    verbosity: Verbosity,
+    words: Words,
+    hint: Hint,
+    // Time of the last key event. Keys from the other half arrive late, so
+    // this is kept from going backwards across the two halves.
+    time: Instant,
+}
+
+impl Engine {
+    pub fn new(settings: &Settings) -> Self {
+        let ms = |key| Duration::from_millis(settings.get(key) as u64);
+        Self {
+            chorder: Chorder::new(ms(settings::CHORD_TERM_MS), ms(settings::TAPPING_TERM_MS)),
+            output: Output::new(settings.get(settings::UNICODE)),
+            last: Last::None,
+            oneshot_shift: false,
+            shift_held: false,
+            shift_used: false,
+            hard_mode: settings.get(settings::HARD_MODE),
+            double_letters: settings.get(settings::DOUBLE_LETTERS),
+            verbosity: settings.get(settings::VERBOSITY),
+            words: Words::new(),
+            hint: Hint::None,
+            time: Instant::MIN,
+        }
+    }
+
+    fn log(&self, parts: &[&'static str]) {
+        if self.verbosity >= Verbosity::Normal {
+            for part in parts {
+                let _ = crate::SERIAL_CHANNEL.try_send(*part);
+            }
+        }
+    }
+
+    // A key from either half, looked up in the layout by position
+    pub fn matrix_event(&mut self, event: MatrixEvent) {
+        let (position, down, at) = match event {
+            MatrixEvent::KeyDown(position, at) => (position, true, at),
+            MatrixEvent::KeyUp(position, at) => (position, false, at),
+        };
+        self.time = self.time.max(at);
+
+        if self.verbosity == Verbosity::Verbose {
+            position.send_name();
+            let _ = crate::SERIAL_CHANNEL.try_send(if down { " down\r\n" } else { " up\r\n" });
+        }
+
+        let Some(key) = layout::keycode(position) else {
+            return;
+        };
+        if down {
+            self.key_down(key, self.time);
+        } else {
+            self.key_up(key, self.time);
+        }
+    }
+
+    pub fn key_down(&mut self, key: char, now: Instant) {
+        if key == chord::SHIFT {
+            self.shift_held = true;
+            self.shift_used = false;
+            return;
+        }
+
+        if let Some(resolved) = self.chorder.key_down(key, now) {
+            self.resolve(resolved, now);
+        }
+    }
+
+    pub fn key_up(&mut self, key: char, now: Instant) {
+        if key == chord::SHIFT {
+            self.shift_held = false;
+            if !self.shift_used {
+                self.oneshot_shift = true;
+            }
+            return;
+        }
+
+        if let Some(resolved) = self.chorder.key_up(key, now) {
+            self.resolve(resolved, now);
+        }
+    }
+
+    fn resolve(&mut self, resolved: Resolved, now: Instant) {
+        match resolved {
+            Resolved::Chord { chord, held } => match chord.behavior {
+                Behavior::Text => {
+                    let variant = match (held, self.take_shift()) {
+                        (false, false) => Variant::Tap,
+                        (false, true) => Variant::Shifted,
+                        (true, false) => Variant::Held,
+                        (true, true) => Variant::HeldShifted,
+                    };
+                    self.hint = Hint::None;
+                    self.chord(chord, variant);
+                }
+                Behavior::DeleteWord => self.delete_word(),
+                Behavior::Bootloader => {
+                    self.log(&["Bootloader\r\n"]);
+                    boot::request();
+                }
+                Behavior::Key(key) => self.key(key, now),
+            },
+            Resolved::Keys(keys) => {
+                for key in keys.iter() {
+                    self.key(key, now);
+                }
+            }
+        }
+    }
+
+    fn key(&mut self, key: char, now: Instant) {
+        let hint = core::mem::replace(&mut self.hint, Hint::None);
+        if key == '\t' && self.tab(hint, now) {
+            return;
+        }
+
+        if key == chord::DUP {
+            self.dup();
+            return;
+        }
+
+        if matches!(key, '.' | ',') && self.punctuate(key) {
+            self.last = Last::Key(key);
+            return;
+        }
+
+        if self.hard_mode && Words::is_boundary(key) && self.missed_chord(now) {
+            self.last = Last::None;
+            return;
+        }
+
+        if self.hard_mode && self.doubled(key) {
+            return;
+        }
+
+        let key = if key.is_ascii_alphabetic() && self.take_shift() {
+            key.to_ascii_uppercase()
+        } else {
+            key
+        };
+
+        self.output.tap(key);
+        self.words.track(key);
+        self.last = Last::Key(key);
+    }
+
+    // In hard mode, a word typed letter by letter that a hard chord would
+    // have produced is deleted, along with the boundary that completed it
+    fn missed_chord(&mut self, now: Instant) -> bool {
+        let Some(chord) = self.words.chord() else {
+            return false;
+        };
+        if !chord.hard {
+            return false;
+        }
+
+        self.log(&["Missed chord ", chord.tap[0].text, "\r\n"]);
+
+        self.output.backspace(self.words.len());
+        self.words.clear();
+        self.hint = Hint::Armed { chord, since: now };
+        true
+    }
+
+    // In hard mode, double letters must be typed with ⨧ rather than by
+    // pressing the same key again
+    fn doubled(&mut self, key: char) -> bool {
+        let Last::Key(last) = self.last else {
+            return false;
+        };
+        if !key.is_ascii_alphabetic()
+            || !last.eq_ignore_ascii_case(&key)
+            || hard::DOUBLE_EXEMPT.contains(&key.to_ascii_lowercase())
+        {
+            return false;
+        }
+
+        self.log(&["Double letter, use dup\r\n"]);
+
+        match self.double_letters {
+            Double::Warn => false,
+            Double::Swallow => true,
+            Double::Backspace => {
+                self.output.backspace(1);
+                self.words.track('\x08');
+                self.last = Last::None;
+                true
+            }
+        }
+    }
+
+    fn tab(&mut self, hint: Hint, now: Instant) -> bool {
+        let expired =
+            |since: Instant| now.duration_since(since) >= Duration::from_millis(HINT_TIMEOUT_MS);
+
+        match hint {
+            Hint::Armed { chord, since } if !expired(since) => {
+                self.words.interrupt();
+                self.output.chord_start();
+                for (i, key) in chord.keys.iter().enumerate() {
+                    if i > 0 {
+                        self.output.tap('+');
+                    }
+                    self.output.text(key_name(*key));
+                }
+                self.output.chord_end();
+                self.hint = Hint::Shown { since: now };
+                self.last = Last::None;
+                true
+            }
+            Hint::Shown { since } if !expired(since) => {
+                self.last = Last::None;
+                // Back at the boundary where the missed word was deleted
+                self.words.clear();
+                self.output.undo_chord()
+            }
+            _ => false,
+        }
+    }
+
+    // Whether the next letter or chord is shifted, consuming the oneshot
+    fn take_shift(&mut self) -> bool {
+        if self.shift_held {
+            self.shift_used = true;
+            self.oneshot_shift = false;
+            return true;
+        }
+        core::mem::take(&mut self.oneshot_shift)
+    }
+
+    // Directly after a chord, `.` and `,` replace its trailing space with the
+    // punctuation and a space; `.` also shifts the next letter or chord. The
+    // edit is attributed to the chord so undo still removes all of it.
+    fn punctuate(&mut self, key: char) -> bool {
+        if !matches!(self.last, Last::Chord(_)) || !self.output.ends_with_chord_space() {
+            return false;
+        }
+
+        self.output.chord_resume();
+        self.output.backspace(1);
+        self.output.tap(key);
+        self.output.tap(' ');
+        self.output.chord_end();
+        self.words.clear();
+
+        if key == '.' {
+            self.oneshot_shift = true;
+        }
+        true
+    }
+
+    // Type the expansion at `index`, attributing it to the chord so it can
+    // later be cycled or undone
+    fn expand(&mut self, chord: &'static Chord, variant: Variant, index: usize) {
+        let expansion = chord.expansions(variant)[index];
+        self.log(&["Chord ", expansion.text, "\r\n"]);
+
+        self.words.interrupt();
+        self.output.chord_start();
+        if chord.capitalize(variant) {
+            self.output.capitalized(expansion.text);
+        } else {
+            self.output.text(expansion.text);
+        }
+        if !expansion.exact {
+            self.output.tap(' ');
+            // The space ends the word, so hard mode checks the next one
+            self.words.clear();
+        }
+        self.output.chord_end();
+    }
+
+    fn chord(&mut self, chord: &'static Chord, variant: Variant) {
+        self.expand(chord, variant, 0);
+        self.last = Last::Chord(Cycle {
+            chord,
+            variant,
+            index: 0,
+        });
+    }
+
+    fn delete_word(&mut self) {
+        let hint = core::mem::replace(&mut self.hint, Hint::None);
+        if matches!(hint, Hint::Shown { .. }) {
+            // Erasing the hint goes back to where the missed word was deleted
+            self.words.clear();
+        } else {
+            self.words.interrupt();
+        }
+        if self.output.undo_chord() {
+            self.log(&["Undo chord\r\n"]);
+        } else {
+            self.output.tap_modified(output::MODIFIER_LEFT_ALT, '\x08');
+        }
+        self.last = Last::None;
+    }
+
+    fn dup(&mut self) {
+        match self.last {
+            Last::None => {}
+            Last::Key(key) => {
+                self.output.tap(key);
+                self.words.track(key);
+            }
+            Last::Chord(cycle) => {
+                let expansions = cycle.chord.expansions(cycle.variant);
+                if expansions.len() > 1 && self.output.undo_chord() {
+                    let index = (cycle.index + 1) % expansions.len();
+                    self.expand(cycle.chord, cycle.variant, index);
+                    self.last = Last::Chord(Cycle { index, ..cycle });
+                } else {
+                    self.expand(cycle.chord, cycle.variant, cycle.index);
+                }
+            }
+        }
+    }
+}
+
+// How a key is spelled out when showing a chord's keys
+fn key_name(key: char) -> &'static str {
+    match key {
+        ' ' => "space",
+        '\x08' => "backspace",
+        '\n' => "enter",
+        '\t' => "tab",
+        chord::DUP => "dup",
+        chord::SHIFT => "shift",
+        'a'..='z' => {
+            const LETTERS: &str = "abcdefghijklmnopqrstuvwxyz";
+            let i = (key as u8 - b'a') as usize;
+            &LETTERS[i..i + 1]
+        }
+        ',' => ",",
+        '.' => ".",
+        '\'' => "'",
+        _ => "?",
+    }
+}
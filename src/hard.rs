@@ -0,0 +1,147 @@
+use crate::chord::{CHORDS, Chord};
+
+pub const DEFAULT_HARD_MODE: bool = false;
+
+// What happens when a letter is typed twice in a row instead of using ⨧
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Double {
+    // Drop the second letter
+    Swallow,
+    // Drop the second letter and erase the first
+    Backspace,
+    // Type it anyway, only reporting it on the sidechannel
+    Warn,
+}
+
+pub const DEFAULT_DOUBLE_LETTERS: Double = Double::Swallow;
+// Letters that may be doubled by hand
+pub const DOUBLE_EXEMPT: &[char] = &[];
+
+const WORD_LEN: usize = 32;
+
+// Indices into CHORDS ordered by each chord's word, so lookup is a binary
+// search over a table that lives in flash
+static WORDS: [usize; CHORDS.len()] = sorted_words();
+
+// The word a chord stands for is its first tap expansion
+const fn word(chord: &Chord) -> &'static [u8] {
+    match chord.tap.first() {
+        Some(expansion) => expansion.text.as_bytes(),
+        None => &[],
+    }
+}
+
+const fn compare(a: &[u8], b: &[u8]) -> core::cmp::Ordering {
+    let mut i = 0;
+    while i < a.len() && i < b.len() {
+        if a[i] < b[i] {
+            return core::cmp::Ordering::Less;
+        }
+        if a[i] > b[i] {
+            return core::cmp::Ordering::Greater;
+        }
+        i += 1;
+    }
+    if a.len() < b.len() {
+        core::cmp::Ordering::Less
+    } else if a.len() > b.len() {
+        core::cmp::Ordering::Greater
+    } else {
+        core::cmp::Ordering::Equal
+    }
+}
+
+const fn sorted_words() -> [usize; CHORDS.len()] {
+    let mut words = [0; CHORDS.len()];
+    let mut i = 0;
+    while i < words.len() {
+        words[i] = i;
+        i += 1;
+    }
+
+    // Insertion sort, since iterators and sort are not available in const fn
+    let mut i = 1;
+    while i < words.len() {
+        let mut j = i;
+        while j > 0 {
+            let a = word(&CHORDS[words[j - 1]]);
+            let b = word(&CHORDS[words[j]]);
+            if !matches!(compare(a, b), core::cmp::Ordering::Greater) {
+                break;
+            }
+            let swap = words[j - 1];
+            words[j - 1] = words[j];
+            words[j] = swap;
+            j -= 1;
+        }
+        i += 1;
+    }
+    words
+}
+
+pub fn lookup(word: &[u8]) -> Option<&'static Chord> {
+    WORDS
+        .binary_search_by(|&i| compare(self::word(&CHORDS[i]), word))
+        .ok()
+        .map(|i| &CHORDS[WORDS[i]])
+}
+
+// Letters typed one at a time since the last word boundary, lowercased
+pub struct Words {
+    buf: [u8; WORD_LEN],
+    len: usize,
+    // Set when the word is too long to track, or when anything other than
+    // individual keystrokes was typed in the middle of it
+    broken: bool,
+}
+
+impl Words {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; WORD_LEN],
+            len: 0,
+            broken: false,
+        }
+    }
+
+    pub fn is_boundary(key: char) -> bool {
+        matches!(key, ' ' | '\n' | '\t' | '.' | ',' | '!' | '?' | ';' | ':')
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.broken = false;
+    }
+
+    // Forget the current word, which no longer consists only of keystrokes
+    pub fn interrupt(&mut self) {
+        self.len = 0;
+        self.broken = true;
+    }
+
+    // Follow an individually typed key
+    pub fn track(&mut self, key: char) {
+        match key {
+            'a'..='z' | 'A'..='Z' | '\'' if self.len < WORD_LEN => {
+                self.buf[self.len] = key.to_ascii_lowercase() as u8;
+                self.len += 1;
+            }
+            '\x08' if self.len > 0 => self.len -= 1,
+            _ if Self::is_boundary(key) => self.clear(),
+            _ => self.interrupt(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // The chord for the word typed so far, if it was typed entirely letter
+    // by letter
+    pub fn chord(&self) -> Option<&'static Chord> {
+        if self.broken || self.len == 0 {
+            return None;
+        }
+        lookup(&self.buf[..self.len])
+    }
+}
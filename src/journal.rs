@@ -0,0 +1,293 @@
+// Power-fail-safe record log over the CONFIG region.
+//
+// Records go into fixed-size slots and are never rewritten in place; on
+// load the valid record with the highest sequence number wins. When a
+// sector fills up, writing moves on to the next one, erasing it first unless
+// `prepare` already has, so wear is spread over every sector and an
+// interrupted write or erase never touches the sector holding the latest
+// record.
+
+pub const SECTOR_SIZE: u32 = 4096;
+pub const SECTORS: u32 = 4;
+
+// Records take up as many consecutive slots as they need, within a sector
+const SLOT_SIZE: usize = 64;
+const SLOTS_PER_SECTOR: u32 = SECTOR_SIZE / SLOT_SIZE as u32;
+const SLOTS: u32 = SECTORS * SLOTS_PER_SECTOR;
+const MAX_RECORD: usize = 4 * SLOT_SIZE;
+
+// magic, crc, seq, version, len; the crc covers everything after itself
+const HEADER_SIZE: usize = 20;
+pub const MAX_PAYLOAD: usize = MAX_RECORD - HEADER_SIZE;
+
+const MAGIC: u32 = 0x1113_C0F7;
+const ERASED: u8 = 0xFF;
+
+// The flash behind the journal, addressed relative to the start of the region
+pub trait Storage {
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), &'static str>;
+    // Programming only clears bits, so the bytes must have been erased
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), &'static str>;
+    async fn erase(&mut self, sector: u32) -> Result<(), &'static str>;
+}
+
+pub struct Entry {
+    pub seq: u32,
+    pub version: u32,
+    len: usize,
+    payload: [u8; MAX_PAYLOAD],
+}
+
+impl Entry {
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.len]
+    }
+}
+
+// CRC-32 (IEEE 802.3)
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn word(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+// Slots taken by a record with `len` bytes of payload
+fn span(len: usize) -> u32 {
+    (HEADER_SIZE + len).div_ceil(SLOT_SIZE) as u32
+}
+
+fn encode(seq: u32, version: u32, payload: &[u8]) -> [u8; MAX_RECORD] {
+    let mut record = [ERASED; MAX_RECORD];
+    record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    record[8..12].copy_from_slice(&seq.to_le_bytes());
+    record[12..16].copy_from_slice(&version.to_le_bytes());
+    record[16..20].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    record[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+    let crc = crc32(&record[8..HEADER_SIZE + payload.len()]);
+    record[4..8].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+// A torn write, a half-erased sector or anything that is not a record
+// fails to decode
+fn decode(record: &[u8]) -> Option<Entry> {
+    let len = word(record, 16) as usize;
+    // A length left erased by a torn header reads as 0xFFFF_FFFF, which
+    // would overflow the sums below
+    if word(record, 0) != MAGIC
+        || len > MAX_PAYLOAD
+        || HEADER_SIZE + len > record.len()
+        || crc32(&record[8..HEADER_SIZE + len]) != word(record, 4)
+    {
+        return None;
+    }
+
+    let mut payload = [0; MAX_PAYLOAD];
+    payload[..len].copy_from_slice(&record[HEADER_SIZE..HEADER_SIZE + len]);
+    Some(Entry {
+        seq: word(record, 8),
+        version: word(record, 12),
+        len,
+        payload,
+    })
+}
+
+// Where the newest record is
+#[derive(Clone, Copy)]
+struct Head {
+    slot: u32,
+    // The slot after it
+    end: u32,
+    seq: u32,
+}
+
+pub struct Journal<S> {
+    storage: S,
+    // Found by scanning the whole region once, then kept up to date by
+    // `append` so saving does not have to scan again
+    head: Option<Head>,
+    scanned: bool,
+    // The sector `prepare` last left erased, until something is written to it
+    prepared: Option<u32>,
+}
+
+impl<S: Storage> Journal<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            storage,
+            head: None,
+            scanned: false,
+            prepared: None,
+        }
+    }
+
+    pub fn storage(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    // The valid record starting at `slot`, if any
+    async fn record(&mut self, slot: u32) -> Result<Option<Entry>, &'static str> {
+        let mut record = [0; MAX_RECORD];
+        let offset = slot * SLOT_SIZE as u32;
+        self.storage
+            .read(offset, &mut record[..HEADER_SIZE])
+            .await?;
+        let payload = word(&record, 16) as usize;
+        if word(&record, 0) != MAGIC || payload > MAX_PAYLOAD {
+            return Ok(None);
+        }
+
+        // Whole slots, since flash is read a word at a time. Records never
+        // cross into the next sector.
+        let room = (SLOTS_PER_SECTOR - slot % SLOTS_PER_SECTOR) as usize * SLOT_SIZE;
+        let len = (span(payload) as usize * SLOT_SIZE).min(room);
+        self.storage
+            .read(offset + HEADER_SIZE as u32, &mut record[HEADER_SIZE..len])
+            .await?;
+        Ok(decode(&record[..len]))
+    }
+
+    async fn erased(&mut self, slot: u32, span: u32) -> Result<bool, &'static str> {
+        let mut bytes = [0; SLOT_SIZE];
+        for slot in slot..slot + span {
+            self.storage
+                .read(slot * SLOT_SIZE as u32, &mut bytes)
+                .await?;
+            if bytes != [ERASED; SLOT_SIZE] {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Find the newest valid record
+    async fn scan(&mut self) -> Result<Option<Head>, &'static str> {
+        let mut head: Option<Head> = None;
+        let mut slot = 0;
+        while slot < SLOTS {
+            let Some(entry) = self.record(slot).await? else {
+                slot += 1;
+                continue;
+            };
+            let end = slot + span(entry.len);
+            if head.is_none_or(|newest| entry.seq > newest.seq) {
+                head = Some(Head {
+                    slot,
+                    end,
+                    seq: entry.seq,
+                });
+            }
+            slot = end;
+        }
+        self.head = head;
+        self.scanned = true;
+        Ok(head)
+    }
+
+    async fn head(&mut self) -> Result<Option<Head>, &'static str> {
+        if self.scanned {
+            Ok(self.head)
+        } else {
+            self.scan().await
+        }
+    }
+
+    pub async fn load(&mut self) -> Result<Option<Entry>, &'static str> {
+        let Some(head) = self.scan().await? else {
+            return Ok(None);
+        };
+        self.record(head.slot).await
+    }
+
+    // Erase the sector the next append moves on to once the newest record's
+    // sector is full, so appending only has to program. Erasing is the longest
+    // stall, so this is meant for when nothing else needs to run.
+    pub async fn prepare(&mut self) -> Result<(), &'static str> {
+        let sector = match self.head().await? {
+            Some(head) => (head.slot / SLOTS_PER_SECTOR + 1) % SECTORS,
+            None => 0,
+        };
+        if self.prepared == Some(sector) {
+            return Ok(());
+        }
+        let first = sector * SLOTS_PER_SECTOR;
+        if !self.erased(first, SLOTS_PER_SECTOR).await? {
+            self.storage.erase(sector).await?;
+        }
+        self.prepared = Some(sector);
+        Ok(())
+    }
+
+    pub async fn append(&mut self, version: u32, payload: &[u8]) -> Result<(), &'static str> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err("Record too large");
+        }
+
+        let (next, seq, current) = match self.head().await? {
+            Some(head) => (head.end, head.seq + 1, Some(head.slot / SLOTS_PER_SECTOR)),
+            None => (0, 1, None),
+        };
+        let record = encode(seq, version, payload);
+        let span = span(payload.len());
+        let bytes = &record[..span as usize * SLOT_SIZE];
+
+        let mut slot = next % SLOTS;
+        let mut tried = 0;
+        while tried < SLOTS {
+            if slot.is_multiple_of(SLOTS_PER_SECTOR) {
+                let sector = slot / SLOTS_PER_SECTOR;
+                // Wrapped all the way around without finding a usable slot
+                if Some(sector) == current {
+                    break;
+                }
+                if self.prepared.take() != Some(sector)
+                    && !self.erased(slot, SLOTS_PER_SECTOR).await?
+                {
+                    self.storage.erase(sector).await?;
+                    // Let USB and scanning catch up after the longest stall
+                    embassy_futures::yield_now().await;
+                }
+            }
+
+            let sector_end = (slot / SLOTS_PER_SECTOR + 1) * SLOTS_PER_SECTOR;
+            let step = if slot + span > sector_end {
+                sector_end - slot
+            } else if !self.erased(slot, span).await? {
+                // Left dirty by an interrupted write
+                1
+            } else {
+                self.storage.write(slot * SLOT_SIZE as u32, bytes).await?;
+                if self
+                    .record(slot)
+                    .await?
+                    .is_some_and(|entry| entry.seq == seq)
+                {
+                    self.head = Some(Head {
+                        slot,
+                        end: slot + span,
+                        seq,
+                    });
+                    return Ok(());
+                }
+                span
+            };
+            slot = (slot + step) % SLOTS;
+            tried += step;
+        }
+
+        Err("No usable flash slot")
+    }
+}
@@ -1,30 +1,28 @@
+use crate::clock::Clock;
+use crate::debounce::{self, Debounce};
+use crate::debounced::{Debounced, KeypinEvent, event};
+use crate::matrix::{MatrixEvent, Oldest};
+use crate::position::{self, KeyPosition};
+use core::future::Future;
 use core::task::Poll;
-use embassy_rp::gpio::{Input, Pull};
+use embassy_rp::gpio::{Input, Pin, Pull};
+use embassy_time::Instant;
 use futures_core::Stream;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum KeypinEvent {
-    Down,
-    Up,
-}
-
 pub struct Keypin {
     gpio: Input<'static>,
-    pub label: &'static str,
-    pub keycode: Option<char>,
+    // The pin's bit in the SIO input register
+    pub mask: u32,
+    pub position: KeyPosition,
     is_down: bool,
 }
 
 impl Keypin {
-    pub fn new(
-        pin: embassy_rp::Peri<'static, impl embassy_rp::gpio::Pin>,
-        label: &'static str,
-        keycode: Option<char>,
-    ) -> Self {
+    pub fn new(pin: embassy_rp::Peri<'static, impl Pin>, position: KeyPosition) -> Self {
         Self {
+            mask: 1 << pin.pin(),
+            position,
             gpio: Input::new(pin, Pull::Up),
-            label,
-            keycode,
             is_down: false,
         }
     }
@@ -38,6 +36,12 @@ impl Stream for Keypin {
         cx: &mut core::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
+        // Read the level first so a pin that has already moved costs no wait
+        // future; one is only set up to register the waker on a quiet pin
+        if this.gpio.is_low() != this.is_down {
+            this.is_down = !this.is_down;
+            return Poll::Ready(Some(event(this.is_down)));
+        }
         if this.is_down {
             let fut = this.gpio.wait_for_high();
             futures_util::pin_mut!(fut);
@@ -61,3 +65,71 @@ impl Stream for Keypin {
         }
     }
 }
+
+// One pin per key, as on the Sweep
+pub struct Matrix<const N: usize, C: Clock> {
+    pins: [Debounced<Keypin, C>; N],
+    // Edges found but not yet returned, at most one per pin
+    pending: [Option<(KeyPosition, KeypinEvent, Instant)>; N],
+    // Where polling starts, moved on every poll so no pin is always last
+    first: usize,
+    oldest: Oldest,
+}
+
+impl<const N: usize, C: Clock> Matrix<N, C> {
+    pub fn new(clock: C, debounce: Debounce, pins: [Keypin; N]) -> Self {
+        const {
+            assert!(
+                N == position::KEYS_PER_HAND as usize,
+                "Expected a pin per key"
+            )
+        };
+        Self {
+            pins: pins.map(|pin| {
+                let debounce = debounce::for_key(Some(pin.position), debounce);
+                Debounced::new(pin, debounce, clock)
+            }),
+            pending: [None; N],
+            first: 0,
+            oldest: Oldest::new(),
+        }
+    }
+}
+
+impl<const N: usize, C: Clock> Stream for Matrix<N, C> {
+    type Item = MatrixEvent;
+
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        // Collect an edge from every pin rather than stopping at the first,
+        // so keys pressed together are all seen in the same poll
+        for i in 0..N {
+            let p = (this.first + i) % N;
+            if this.pending[p].is_none()
+                && let Poll::Ready(Some((event, at))) =
+                    core::pin::Pin::new(&mut this.pins[p]).poll_next(cx)
+            {
+                this.pending[p] = Some((this.pins[p].inner.position, event, at));
+            }
+        }
+        this.first = (this.first + 1) % N;
+
+        // Ties go round-robin like the polling
+        let edges = (0..N)
+            .map(|i| (this.first + i) % N)
+            .filter_map(|p| this.pending[p].map(|edge| ((p, edge), edge.2)));
+        let Some(((p, (position, event, _)), at)) = this.oldest.pick(edges) else {
+            return Poll::Pending;
+        };
+        this.pending[p] = None;
+        Poll::Ready(Some(MatrixEvent::new(
+            position,
+            event == KeypinEvent::Down,
+            at,
+        )))
+    }
+}
@@ -0,0 +1,34 @@
+use crate::chord;
+use crate::position::{self, Hand, KeyPosition, Row};
+
+// The whole board as seen from above, left hand first. Each half only
+// reports positions, and whichever half is primary looks them up here, so
+// the output is the same whichever half has the USB cable.
+const ROWS: [[char; 2 * position::COLS as usize]; 3] = [
+    ['w', 'l', 'y', 'p', 'b', 'z', 'f', 'o', 'u', '\''],
+    ['c', 'r', 's', 't', 'g', 'm', 'n', 'e', 'i', 'a'],
+    ['q', 'j', 'v', 'd', 'k', 'f', 'h', '.', ',', '\n'],
+];
+
+const THUMBS: [Option<char>; 2 * position::THUMBS as usize] = [
+    Some(chord::SHIFT),
+    Some('\x08'),
+    Some(' '),
+    Some(chord::DUP),
+];
+
+pub fn keycode(position: KeyPosition) -> Option<char> {
+    let cols = position::cols(position.row);
+    // Columns count from the outer edge, which is on the right for the
+    // right hand
+    let across = match position.hand {
+        Hand::Left => position.col - 1,
+        Hand::Right => 2 * cols - position.col,
+    } as usize;
+    match position.row {
+        Row::Top => Some(ROWS[0][across]),
+        Row::Home => Some(ROWS[1][across]),
+        Row::Bottom => Some(ROWS[2][across]),
+        Row::Thumb => THUMBS[across],
+    }
+}
@@ -0,0 +1,163 @@
+// What the halves say to each other over the sync wire and how long it takes,
+// apart from driving the pin in sync.rs, so the simulator can put the same
+// link between its two halves.
+use crate::matrix::{MatrixEvent, Oldest};
+use crate::position::KeyPosition;
+use embassy_time::{Duration, Instant};
+
+// Slow enough for async waits to sample each bit near its middle while USB
+// is busy, and fast enough that a key is in within a few ms
+pub const BIT_TIME: Duration = Duration::from_micros(100);
+// A sync pulse two bits long, 8 data bits and even parity
+pub const BYTE_BITS: u64 = 11;
+pub const MAX_MESSAGE_LEN: usize = 3;
+// Long enough for any message sent back to back
+pub const MESSAGE_TIME: Duration =
+    Duration::from_micros(BIT_TIME.as_micros() * BYTE_BITS * MAX_MESSAGE_LEN as u64);
+
+#[derive(Debug, Clone, Copy)]
+pub enum SyncMessage {
+    // A key on the secondary half went down or up, by position index, and
+    // how many ms before the message started it did
+    KeyDown { index: u8, age: u8 },
+    KeyUp { index: u8, age: u8 },
+}
+
+impl SyncMessage {
+    // The secondary's key, for a message starting at `now`. Keys queued for
+    // longer than an age can say are sent as the oldest it can.
+    pub fn new(event: MatrixEvent, now: Instant) -> Self {
+        let (position, down, at) = match event {
+            MatrixEvent::KeyDown(position, at) => (position, true, at),
+            MatrixEvent::KeyUp(position, at) => (position, false, at),
+        };
+        let index = position.index;
+        let age = now
+            .saturating_duration_since(at)
+            .as_millis()
+            .min(u8::MAX as u64) as u8;
+        if down {
+            SyncMessage::KeyDown { index, age }
+        } else {
+            SyncMessage::KeyUp { index, age }
+        }
+    }
+
+    // The key on the primary, dated by its own clock from when the message
+    // started, which the link's own delay does not change
+    pub fn event(self, started: Instant) -> Option<MatrixEvent> {
+        let (SyncMessage::KeyDown { index, age } | SyncMessage::KeyUp { index, age }) = self;
+        let position = KeyPosition::from_index(index)?;
+        let at = started
+            .checked_sub(Duration::from_millis(age as u64))
+            .unwrap_or(Instant::MIN);
+        Some(MatrixEvent::new(
+            position,
+            matches!(self, SyncMessage::KeyDown { .. }),
+            at,
+        ))
+    }
+
+    pub fn msg_len(msg_type: u8) -> Option<usize> {
+        match msg_type {
+            1 | 2 => Some(2), // Key down/up: the position index and age
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(self) -> ([u8; MAX_MESSAGE_LEN], usize) {
+        match self {
+            SyncMessage::KeyDown { index, age } => ([1, index, age], 3), // msg_type + payload
+            SyncMessage::KeyUp { index, age } => ([2, index, age], 3),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (index, age) = (*bytes.get(1)?, *bytes.get(2)?);
+        match bytes.first()? {
+            1 => Some(SyncMessage::KeyDown { index, age }),
+            2 => Some(SyncMessage::KeyUp { index, age }),
+            _ => None,
+        }
+    }
+}
+
+// What the primary reads off the sync wire
+pub enum Received {
+    // A message's first sync pulse
+    Started,
+    // With when it started
+    Message(SyncMessage, Instant),
+    // Not a whole message after all
+    Failed,
+}
+
+// Local keys that can be held at once, far more than can move during a message
+const HELD: usize = 16;
+
+// Keys from both halves in the order they moved. The other half's keys arrive
+// a message time after they moved, so a local key that moved meanwhile would
+// otherwise get to the engine first, and could end a chord before the key
+// that started it. Local keys are held while a message is on the wire and go
+// out with it oldest first.
+pub struct Merge {
+    events: [Option<MatrixEvent>; HELD],
+    receiving: bool,
+    oldest: Oldest,
+}
+
+impl Merge {
+    pub const fn new() -> Self {
+        Self {
+            events: [None; HELD],
+            receiving: false,
+            oldest: Oldest::new(),
+        }
+    }
+
+    pub fn local(&mut self, event: MatrixEvent) {
+        self.push(event);
+    }
+
+    // Room is made by `next` before another event comes in
+    fn push(&mut self, event: MatrixEvent) {
+        if let Some(slot) = self.events.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(event);
+        }
+    }
+
+    pub fn received(&mut self, received: Received) {
+        match received {
+            Received::Started => self.receiving = true,
+            Received::Message(message, started) => {
+                self.receiving = false;
+                match message.event(started) {
+                    Some(event) => self.push(event),
+                    None => {
+                        let _ =
+                            crate::SERIAL_CHANNEL.try_send("Unknown key from the other half\r\n");
+                    }
+                }
+            }
+            Received::Failed => self.receiving = false,
+        }
+    }
+
+    // The next key for the engine, if none could still be on the wire ahead
+    // of it. Once the held keys fill up the oldest goes anyway, in case a
+    // message never ends.
+    pub fn next(&mut self) -> Option<MatrixEvent> {
+        if self.receiving && self.events.iter().any(Option::is_none) {
+            return None;
+        }
+        let events = self.events.iter().enumerate().filter_map(|(i, event)| {
+            event.map(|(MatrixEvent::KeyDown(_, at) | MatrixEvent::KeyUp(_, at))| (i, at))
+        });
+        let (i, at) = self.oldest.pick(events)?;
+        let event = self.events[i].take()?;
+        Some(match event {
+            MatrixEvent::KeyDown(position, _) => MatrixEvent::KeyDown(position, at),
+            MatrixEvent::KeyUp(position, _) => MatrixEvent::KeyUp(position, at),
+        })
+    }
+}
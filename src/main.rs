@@ -1,13 +1,36 @@
 #![no_std]
 #![no_main]
 
+#[cfg(not(feature = "matrix-scan"))]
+mod bank;
+mod boot;
+mod chord;
+mod clock;
+mod console;
 mod debounce;
+#[cfg(not(feature = "matrix-scan"))]
+mod debounced;
+mod engine;
+mod hard;
+mod journal;
+#[cfg(not(feature = "matrix-scan"))]
 mod keypin;
+mod layout;
+mod link;
 mod matrix;
+mod output;
+mod position;
+#[cfg(feature = "matrix-scan")]
+mod scan;
+mod settings;
 mod stash;
 mod sync;
 
+use clock::SystemClock;
+use console::Command;
+use core::sync::atomic::Ordering;
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
 use embassy_rp::bind_interrupts;
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::{Driver, InterruptHandler};
@@ -18,10 +41,16 @@ use embassy_time::Timer;
 use embassy_usb::class::cdc_acm::{CdcAcmClass, State as AcmState};
 use embassy_usb::class::hid::{Config as HidConfig, HidReaderWriter, State as HidState};
 use embassy_usb::{Builder, Config as UsbConfig};
+use engine::Engine;
 use futures_util::StreamExt;
-use keypin::Keypin;
-use matrix::{Matrix, MatrixEvent};
+#[cfg(not(feature = "matrix-scan"))]
+use futures_util::future;
+#[cfg(not(feature = "matrix-scan"))]
+use keypin::{Keypin, Matrix};
+use matrix::MatrixEvent;
 use panic_halt as _;
+#[cfg(feature = "matrix-scan")]
+use position::{KeyPosition, Row};
 use stash::Stash;
 use static_cell::StaticCell;
 use usbd_hid::descriptor::{KeyboardReport, MouseReport, SerializedDescriptor};
@@ -33,6 +62,7 @@ const USB_DESCRIPTOR_BUF_SIZE: usize = 512;
 const KEYBOARD_MAX_PACKET_SIZE: usize = 8;
 const HID_POLL_MS: u8 = 1;
 const MOUSE_MAX_PACKET_SIZE: usize = 5;
+const TOUCH_POLL_MS: u64 = 100;
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
@@ -41,26 +71,169 @@ bind_interrupts!(struct Irqs {
 static SERIAL_CHANNEL: Channel<ThreadModeRawMutex, &'static str, SERIAL_CHANNEL_CAPACITY> =
     Channel::new();
 
-static SYNC_RX_CHANNEL: Channel<ThreadModeRawMutex, sync::SyncMessage, 8> = Channel::new();
-static SYNC_TX_CHANNEL: Channel<ThreadModeRawMutex, sync::SyncMessage, 8> = Channel::new();
+static SYNC_RX_CHANNEL: Channel<ThreadModeRawMutex, link::Received, 8> = Channel::new();
+// Keys for the primary, sent by the secondary's sync task
+static SYNC_TX_CHANNEL: Channel<ThreadModeRawMutex, MatrixEvent, 8> = Channel::new();
+
+// The pins wired to keys, in the order `position::SWEEP` lists them, which
+// is checked when building
+#[cfg(not(feature = "matrix-scan"))]
+macro_rules! keypins {
+    ($p:ident, $hand:ident, $($pin:ident),* $(,)?) => {{
+        const _: () = assert!(
+            position::is_sweep_order(&[$(stringify!($pin)),*]),
+            "Key pins out of step with the wiring"
+        );
+        let mut i = 0;
+        [$({
+            i += 1;
+            Keypin::new($p.$pin, position::wired($hand, i - 1))
+        }),*]
+    }};
+}
+
+// Both halves read their keys alike and report them by position, so either
+// can be the primary
+macro_rules! key_matrix {
+    ($p:ident, $config:ident) => {{
+        let hand = $config.get(settings::HAND);
+        let debounce = debounce::Debounce::from_settings(&$config);
+        // Only pins with a key on them are claimed. The rest, including those
+        // not broken out in the Pro Micro form factor, stay as they come out
+        // of reset: pulled down with no function, so they cannot float.
+        #[cfg(not(feature = "matrix-scan"))]
+        let pins = keypins!(
+            $p, hand, PIN_7, PIN_26, PIN_27, PIN_28, PIN_29, PIN_22, PIN_20, PIN_23, PIN_21, PIN_0,
+            PIN_2, PIN_3, PIN_4, PIN_5, PIN_6, PIN_8, PIN_9
+        );
+
+        #[cfg(not(feature = "matrix-scan"))]
+        let matrix = match $config.get(settings::SCANNER) {
+            matrix::Scanner::Interrupt => {
+                future::Either::Left(Matrix::new(SystemClock, debounce, pins))
+            }
+            matrix::Scanner::Bank => {
+                future::Either::Right(bank::BankMatrix::new(SystemClock, debounce, pins))
+            }
+        };
+
+        // Example wiring for a 3x5+2 half on a diode matrix: rows on GP2-GP5,
+        // columns on GP6-GP9 and GP20 from left to right, thumbs on the inner
+        // end of the last row
+        #[cfg(feature = "matrix-scan")]
+        let key = |row, col| Some(KeyPosition::new(hand, row, col));
+        #[cfg(feature = "matrix-scan")]
+        let matrix = scan::ScanMatrix::new(
+            SystemClock,
+            [
+                embassy_rp::gpio::Flex::new($p.PIN_2),
+                embassy_rp::gpio::Flex::new($p.PIN_3),
+                embassy_rp::gpio::Flex::new($p.PIN_4),
+                embassy_rp::gpio::Flex::new($p.PIN_5),
+            ],
+            [
+                embassy_rp::gpio::Flex::new($p.PIN_6),
+                embassy_rp::gpio::Flex::new($p.PIN_7),
+                embassy_rp::gpio::Flex::new($p.PIN_8),
+                embassy_rp::gpio::Flex::new($p.PIN_9),
+                embassy_rp::gpio::Flex::new($p.PIN_20),
+            ],
+            if cfg!(feature = "matrix-row2col") {
+                scan::Diodes::Row2Col
+            } else {
+                scan::Diodes::Col2Row
+            },
+            scan::DEFAULT_SETTLE,
+            debounce,
+            match hand {
+                position::Hand::Left => [
+                    [
+                        key(Row::Top, 1),
+                        key(Row::Top, 2),
+                        key(Row::Top, 3),
+                        key(Row::Top, 4),
+                        key(Row::Top, 5),
+                    ],
+                    [
+                        key(Row::Home, 1),
+                        key(Row::Home, 2),
+                        key(Row::Home, 3),
+                        key(Row::Home, 4),
+                        key(Row::Home, 5),
+                    ],
+                    [
+                        key(Row::Bottom, 1),
+                        key(Row::Bottom, 2),
+                        key(Row::Bottom, 3),
+                        key(Row::Bottom, 4),
+                        key(Row::Bottom, 5),
+                    ],
+                    [None, None, None, key(Row::Thumb, 1), key(Row::Thumb, 2)],
+                ],
+                position::Hand::Right => [
+                    [
+                        key(Row::Top, 5),
+                        key(Row::Top, 4),
+                        key(Row::Top, 3),
+                        key(Row::Top, 2),
+                        key(Row::Top, 1),
+                    ],
+                    [
+                        key(Row::Home, 5),
+                        key(Row::Home, 4),
+                        key(Row::Home, 3),
+                        key(Row::Home, 2),
+                        key(Row::Home, 1),
+                    ],
+                    [
+                        key(Row::Bottom, 5),
+                        key(Row::Bottom, 4),
+                        key(Row::Bottom, 3),
+                        key(Row::Bottom, 2),
+                        key(Row::Bottom, 1),
+                    ],
+                    [key(Row::Thumb, 2), key(Row::Thumb, 1), None, None, None],
+                ],
+            },
+        );
+
+        matrix
+    }};
+}
 
 async fn run_primary(p: embassy_rp::Peripherals) {
-    let mut stash = Stash::new(p.FLASH);
-    let config = match stash.load() {
-        Ok(c) => c,
+    let mut stash = Stash::new(p.FLASH, p.DMA_CH0);
+    match stash.load().await {
+        Ok(stash::Origin::Current) => {}
+        Ok(stash::Origin::Legacy) => {
+            let _ = SERIAL_CHANNEL.try_send("Migrating legacy config\r\n");
+            if let Err(e) = stash.save().await {
+                let _ = SERIAL_CHANNEL.try_send("Failed to save: ");
+                let _ = SERIAL_CHANNEL.try_send(e);
+                let _ = SERIAL_CHANNEL.try_send("\r\n");
+            }
+        }
+        Err(stash::LoadError::Unconfigured) => {
+            let _ = SERIAL_CHANNEL.try_send("No config saved, using defaults\r\n");
+        }
         Err(e) => {
             let _ = SERIAL_CHANNEL.try_send("Failed to load config: ");
-            let _ = SERIAL_CHANNEL.try_send(e);
+            let _ = SERIAL_CHANNEL.try_send(e.as_str());
             let _ = SERIAL_CHANNEL.try_send("\r\n");
-            stash::Config::default()
         }
-    };
+    }
+    // Settings changed from here on apply after the next reboot
+    let config = stash.settings().clone();
+
+    // Nothing is typed before USB is up, so the erase stalls nothing. A
+    // failure leaves the erase to the save that needs it.
+    let _ = stash.prepare().await;
 
-    match config.hand {
-        stash::Hand::Left => {
+    match stash.get(settings::HAND) {
+        position::Hand::Left => {
             let _ = SERIAL_CHANNEL.try_send("Configured as left-handed\r\n");
         }
-        stash::Hand::Right => {
+        position::Hand::Right => {
             let _ = SERIAL_CHANNEL.try_send("Configured as right-handed\r\n");
         }
     }
@@ -129,155 +302,33 @@ async fn run_primary(p: embassy_rp::Peripherals) {
     let mut usb = builder.build();
     let usb = usb.run();
 
-    let mut matrix = match config.hand {
-        stash::Hand::Left => {
-            Matrix::new(
-                config.hand,
-                [
-                    Keypin::new(p.PIN_0, "0", Some('g')),
-                    // 1 is used for UART
-                    Keypin::new(p.PIN_2, "2", Some('q')),
-                    Keypin::new(p.PIN_3, "3", Some('j')),
-                    Keypin::new(p.PIN_4, "4", Some('v')),
-                    Keypin::new(p.PIN_5, "5", Some('d')),
-                    Keypin::new(p.PIN_6, "6", Some('k')),
-                    Keypin::new(p.PIN_7, "7", Some('w')),
-                    Keypin::new(p.PIN_8, "8", None),
-                    Keypin::new(p.PIN_9, "9", Some('\x08')),
-                    // 10 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_10, "10", None),
-                    // 11 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_11, "11", None),
-                    Keypin::new(p.PIN_12, "12", None),
-                    Keypin::new(p.PIN_13, "13", None),
-                    Keypin::new(p.PIN_14, "14", None),
-                    Keypin::new(p.PIN_15, "15", None),
-                    Keypin::new(p.PIN_16, "16", None),
-                    // 17 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_17, "17", None),
-                    // 18 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_18, "18", None),
-                    // 19 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_19, "19", None),
-                    Keypin::new(p.PIN_20, "20", Some('r')),
-                    Keypin::new(p.PIN_21, "21", Some('t')),
-                    Keypin::new(p.PIN_22, "22", Some('c')),
-                    Keypin::new(p.PIN_23, "23", Some('s')),
-                    // 24 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_24, "24", None),
-                    Keypin::new(p.PIN_25, "25", None),
-                    Keypin::new(p.PIN_26, "26", Some('l')),
-                    Keypin::new(p.PIN_27, "27", Some('y')),
-                    Keypin::new(p.PIN_28, "28", Some('p')),
-                    Keypin::new(p.PIN_29, "29", Some('b')),
-                ],
-            )
-        }
-        stash::Hand::Right => {
-            Matrix::new(
-                config.hand,
-                [
-                    Keypin::new(p.PIN_0, "0", Some('m')),
-                    // 1 is used for UART
-                    Keypin::new(p.PIN_2, "2", Some('\n')),
-                    Keypin::new(p.PIN_3, "3", Some(',')),
-                    Keypin::new(p.PIN_4, "4", Some('.')),
-                    Keypin::new(p.PIN_5, "5", Some('h')),
-                    Keypin::new(p.PIN_6, "6", Some('f')),
-                    Keypin::new(p.PIN_7, "7", Some('\'')),
-                    Keypin::new(p.PIN_8, "8", None),
-                    Keypin::new(p.PIN_9, "9", Some(' ')),
-                    // 10 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_10, "10", None),
-                    // 11 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_11, "11", None),
-                    Keypin::new(p.PIN_12, "12", None),
-                    Keypin::new(p.PIN_13, "13", None),
-                    Keypin::new(p.PIN_14, "14", None),
-                    Keypin::new(p.PIN_15, "15", None),
-                    Keypin::new(p.PIN_16, "16", None),
-                    // 17 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_17, "17", None),
-                    // 18 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_18, "18", None),
-                    // 19 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_19, "19", None),
-                    Keypin::new(p.PIN_20, "20", Some('i')),
-                    Keypin::new(p.PIN_21, "21", Some('n')),
-                    Keypin::new(p.PIN_22, "22", Some('a')),
-                    Keypin::new(p.PIN_23, "23", Some('e')),
-                    // 24 is not broken out in Pro Micro form factor
-                    Keypin::new(p.PIN_24, "24", None),
-                    Keypin::new(p.PIN_25, "25", None),
-                    Keypin::new(p.PIN_26, "26", Some('u')),
-                    Keypin::new(p.PIN_27, "27", Some('o')),
-                    Keypin::new(p.PIN_28, "28", Some('f')),
-                    Keypin::new(p.PIN_29, "29", Some('z')),
-                ],
-            )
-        }
-    };
+    let mut matrix = key_matrix!(p, config);
 
     let (_, mut writer) = keyboard.split();
-
+    let mut engine = Engine::new(&config);
     let keyboard = async {
+        let mut merge = link::Merge::new();
         loop {
-            if let Some(event) = matrix.next().await {
-                match event {
-                    MatrixEvent::KeyDown(label, keycode) => {
-                        let _ = SERIAL_CHANNEL.try_send(if config.hand == stash::Hand::Left {
-                            "Left "
-                        } else {
-                            "Right "
-                        });
-                        let _ = SERIAL_CHANNEL.try_send(label);
-                        let _ = SERIAL_CHANNEL.try_send(" down\r\n");
-
-                        if let Some(keycode) = keycode {
-                            let hid_keycode = match keycode {
-                                'a'..='z' => (keycode as u8) - b'a' + 0x04,
-                                'A'..='Z' => (keycode as u8) - b'A' + 0x04,
-                                '\n' => 0x28,
-                                '\x08' => 0x2a,
-                                ' ' => 0x2c,
-                                '\'' => 0x34,
-                                ',' => 0x36,
-                                '.' => 0x37,
-                                _ => 0,
-                            };
-                            let report = KeyboardReport {
-                                modifier: 0,
-                                reserved: 0,
-                                leds: 0,
-                                keycodes: [hid_keycode, 0, 0, 0, 0, 0],
-                            };
-                            let _ = writer.write_serialize(&report).await;
-                        }
-                    }
-                    MatrixEvent::KeyUp(label, keycode) => {
-                        let _ = SERIAL_CHANNEL.try_send(if config.hand == stash::Hand::Left {
-                            "Left "
-                        } else {
-                            "Right "
-                        });
-                        let _ = SERIAL_CHANNEL.try_send(label);
-                        let _ = SERIAL_CHANNEL.try_send(" up\r\n");
-
-                        if keycode.is_some() {
-                            let report = KeyboardReport {
-                                modifier: 0,
-                                reserved: 0,
-                                leds: 0,
-                                keycodes: [0, 0, 0, 0, 0, 0],
-                            };
-                            let _ = writer.write_serialize(&report).await;
-                        }
-                    }
-                }
+            match select(matrix.next(), SYNC_RX_CHANNEL.receive()).await {
+                Either::First(Some(event)) => merge.local(event),
+                Either::First(None) => continue,
+                Either::Second(received) => merge.received(received),
+            }
+            while let Some(event) = merge.next() {
+                console::count(&console::KEY_EVENTS);
+                engine.matrix_event(event);
             }
         }
     };
 
+    let hid_tx = async {
+        loop {
+            let report = output::REPORT_CHANNEL.receive().await;
+            let _ = writer.write_serialize(&report).await;
+            console::count(&console::REPORTS);
+        }
+    };
+
     let serial_tx = async {
         loop {
             Timer::after_millis(1000).await;
@@ -296,106 +347,122 @@ async fn run_primary(p: embassy_rp::Peripherals) {
 
     let serial_rx = async {
         let mut buf = [0u8; USB_MAX_PACKET_SIZE];
+        let mut line = console::Line::new();
+        let mut key_events = 0;
         loop {
             serial_reader.wait_connection().await;
 
             loop {
-                match serial_reader.read_packet(&mut buf).await {
-                    Ok(n) if n > 0 => match buf[0] {
-                        b'L' => {
-                            let mut config = config.clone();
-                            config.hand = stash::Hand::Left;
-                            if let Err(e) = stash.save(config) {
-                                let _ = SERIAL_CHANNEL.try_send("Failed to save: ");
-                                let _ = SERIAL_CHANNEL.try_send(e);
-                                let _ = SERIAL_CHANNEL.try_send("\r\n");
-                            } else {
-                                let _ =
-                                    SERIAL_CHANNEL.try_send("Set hand to Left, rebooting...\r\n");
-                                Timer::after_millis(100).await;
-                                watchdog.trigger_reset();
-                            }
+                // Time out now and then to notice a 1200 baud touch, which
+                // sends no data
+                let n = match select(
+                    serial_reader.read_packet(&mut buf),
+                    Timer::after_millis(TOUCH_POLL_MS),
+                )
+                .await
+                {
+                    Either::First(Ok(n)) => n,
+                    Either::First(Err(_)) => break,
+                    Either::Second(()) => {
+                        // Erase ahead after a save once no key has moved
+                        // for a poll, so the erase does not delay typing
+                        let events = console::KEY_EVENTS.load(Ordering::Relaxed);
+                        if events == key_events {
+                            let _ = stash.prepare().await;
                         }
-                        b'R' => {
-                            let mut config = config.clone();
-                            config.hand = stash::Hand::Right;
-                            if let Err(e) = stash.save(config) {
-                                let _ = SERIAL_CHANNEL.try_send("Failed to save: ");
-                                let _ = SERIAL_CHANNEL.try_send(e);
-                                let _ = SERIAL_CHANNEL.try_send("\r\n");
-                            } else {
-                                let _ =
-                                    SERIAL_CHANNEL.try_send("Set hand to Right, rebooting...\r\n");
-                                Timer::after_millis(100).await;
-                                watchdog.trigger_reset();
-                            }
-                        }
-                        _ => {
-                            let _ = SERIAL_CHANNEL.try_send("Unknown command\r\n");
-                        }
-                    },
-                    Err(_) => break,
-                    _ => {}
+                        key_events = events;
+                        0
+                    }
+                };
+                if serial_reader.line_coding().data_rate() == boot::TOUCH_BAUD
+                    && !serial_reader.dtr()
+                {
+                    boot::request();
                 }
-            }
-        }
-    };
 
-    let sync = sync::primary(p.PIN_1, &SYNC_RX_CHANNEL);
-
-    let sync_handler = async {
-        loop {
-            let msg = SYNC_RX_CHANNEL.receive().await;
-            match msg {
-                sync::SyncMessage::Test(val) => {
-                    let _ = SERIAL_CHANNEL.try_send("Test(");
-                    let d2 = (val / 100) % 10;
-                    let d1 = (val / 10) % 10;
-                    let d0 = val % 10;
-                    for digit in [d2, d1, d0] {
-                        let _ = SERIAL_CHANNEL.try_send(match digit {
-                            0 => "0",
-                            1 => "1",
-                            2 => "2",
-                            3 => "3",
-                            4 => "4",
-                            5 => "5",
-                            6 => "6",
-                            7 => "7",
-                            8 => "8",
-                            9 => "9",
-                            _ => "?",
-                        });
+                for &byte in &buf[..n] {
+                    if !line.push(byte) {
+                        continue;
                     }
-                    let _ = SERIAL_CHANNEL.try_send(")\r\n");
+                    let command = match line.take().and_then(Command::parse) {
+                        Ok(Some(command)) => command,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            console::reply(Err(e));
+                            continue;
+                        }
+                    };
+
+                    let result = match command {
+                        Command::Help => {
+                            console::help();
+                            Ok(())
+                        }
+                        Command::Get(Some(name)) => stash.settings().report_setting(name),
+                        Command::Get(None) => {
+                            stash.settings().report();
+                            Ok(())
+                        }
+                        Command::Set(name, value) => stash.save_named(name, value).await,
+                        Command::Info => {
+                            console::info(&config);
+                            Ok(())
+                        }
+                        Command::Reboot => {
+                            console::reply(Ok(()));
+                            Timer::after_millis(100).await;
+                            watchdog.trigger_reset();
+                            Ok(())
+                        }
+                        Command::Bootloader => {
+                            boot::request();
+                            Ok(())
+                        }
+                        Command::Stats => {
+                            console::stats();
+                            Ok(())
+                        }
+                    };
+                    console::reply(result);
                 }
             }
         }
     };
 
-    embassy_futures::join::join(
+    let sync = sync::primary(
+        p.PIN_1,
+        SystemClock,
+        &SYNC_RX_CHANNEL,
+        config.get(settings::VERBOSITY),
+    );
+
+    embassy_futures::join::join3(
         embassy_futures::join::join5(usb, serial_tx, serial_rx, keyboard, sync),
-        sync_handler,
+        hid_tx,
+        boot::run(),
     )
     .await;
 }
 
 async fn run_secondary(p: embassy_rp::Peripherals) {
-    let stash = Stash::new(p.FLASH);
-    let _config = stash.load().unwrap_or_default();
+    let mut stash = Stash::new(p.FLASH, p.DMA_CH0);
+    let _ = stash.load().await;
+    let config = stash.settings().clone();
+
+    let mut matrix = key_matrix!(p, config);
 
-    let sync = sync::secondary(p.PIN_1, &SYNC_TX_CHANNEL);
+    let sync = sync::secondary(p.PIN_1, SystemClock, &SYNC_TX_CHANNEL);
 
-    let sync_feeder = async {
-        let mut counter = 0u8;
+    // Keys go to the primary by position, to be looked up in its layout
+    let keyboard = async {
         loop {
-            let _ = SYNC_TX_CHANNEL.try_send(sync::SyncMessage::Test(counter));
-            counter = counter.wrapping_add(1);
-            Timer::after_millis(100).await;
+            if let Some(event) = matrix.next().await {
+                SYNC_TX_CHANNEL.send(event).await;
+            }
         }
     };
 
-    embassy_futures::join::join(sync, sync_feeder).await;
+    embassy_futures::join::join(sync, keyboard).await;
 }
 
 async fn detect_usb_connection() -> bool {
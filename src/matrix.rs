@@ -1,51 +1,128 @@
-use crate::debounce::Debounced;
-use crate::keypin::{Keypin, KeypinEvent};
-use crate::stash;
-use core::task::Poll;
-use futures_core::Stream;
+use crate::clock::Clock;
+use crate::position::KeyPosition;
+use core::future::Future;
+use core::task::{Context, Poll};
+use embassy_time::{Duration, Instant};
 
+// How keys wired one per pin are read
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Scanner {
+    // An interrupt future per pin
+    Interrupt,
+    // Every pin at once from the SIO input register, on a timer
+    Bank,
+}
+
+pub const DEFAULT_SCANNER: Scanner = Scanner::Interrupt;
+
+// Each event carries when the key physically got to its new level. Events
+// come out oldest first and their times never go backwards, so the chorder
+// can order them and subtract one from another.
+#[derive(Clone, Copy)]
 pub enum MatrixEvent {
-    KeyDown(&'static str, Option<char>),
-    KeyUp(&'static str, Option<char>),
+    KeyDown(KeyPosition, Instant),
+    KeyUp(KeyPosition, Instant),
 }
 
-pub struct Matrix<const N: usize> {
-    hand: stash::Hand,
-    pins: [Debounced<Keypin>; N],
+impl MatrixEvent {
+    pub fn new(position: KeyPosition, down: bool, at: Instant) -> Self {
+        if down {
+            MatrixEvent::KeyDown(position, at)
+        } else {
+            MatrixEvent::KeyUp(position, at)
+        }
+    }
 }
 
-impl<const N: usize> Matrix<N> {
-    pub fn new(hand: stash::Hand, pins: [Keypin; N]) -> Self {
+// Hands out the edges a scanner found together oldest first, with times that
+// never go backwards
+pub struct Oldest {
+    // Time of the last event returned
+    last: Instant,
+}
+
+impl Oldest {
+    pub const fn new() -> Self {
+        Self { last: Instant::MIN }
+    }
+
+    // The oldest of `edges`, ties going to the first, with the time its event
+    // carries. A deferred edge can be reported after a later eager one, so
+    // that is never earlier than the last one returned.
+    pub fn pick<T>(&mut self, edges: impl Iterator<Item = (T, Instant)>) -> Option<(T, Instant)> {
+        let (edge, at) = edges.min_by_key(|(_, at)| *at)?;
+        Some((edge, self.clamp(at)))
+    }
+
+    // `at`, or the time last returned if that is later
+    fn clamp(&mut self, at: Instant) -> Instant {
+        self.last = self.last.max(at);
+        self.last
+    }
+}
+
+const SCAN_INTERVAL: Duration = Duration::from_millis(1);
+
+// When a sampled scanner next reads its keys, and the order it returns what
+// they did
+pub struct Sampler<C: Clock> {
+    clock: C,
+    next_scan: C::Timer,
+    oldest: Oldest,
+}
+
+impl<C: Clock> Sampler<C> {
+    pub fn new(clock: C) -> Self {
         Self {
-            hand,
-            pins: pins.map(Debounced::new),
+            next_scan: clock.at(Instant::MIN),
+            clock,
+            oldest: Oldest::new(),
         }
     }
 }
 
-impl<const N: usize> Stream for Matrix<N> {
-    type Item = MatrixEvent;
+// A scanner that reads all its keys at once on a timer, keeping the edges
+// its debouncers report in numbered slots until they are returned
+pub trait Sampled {
+    type Clock: Clock;
+
+    fn sampler(&mut self) -> &mut Sampler<Self::Clock>;
 
-    fn poll_next(
-        self: core::pin::Pin<&mut Self>,
-        cx: &mut core::task::Context<'_>,
-    ) -> Poll<Option<Self::Item>> {
-        let this = self.get_mut();
+    // The slots holding an edge, with when its key got to the new level
+    fn pending(&self) -> impl Iterator<Item = (usize, Instant)>;
 
-        for debounced_pin in this.pins.iter_mut() {
-            let pin_label = debounced_pin.inner.label;
-            let pin_keycode = debounced_pin.inner.keycode;
+    // Empty `slot`, giving its key unless none is wired there, and whether
+    // the key went down
+    fn take(&mut self, slot: usize) -> Option<(KeyPosition, bool)>;
 
-            let mut pin = core::pin::Pin::new(debounced_pin);
-            if let Poll::Ready(Some(event)) = pin.as_mut().poll_next(cx) {
-                let matrix_event = match event {
-                    KeypinEvent::Down => MatrixEvent::KeyDown(pin_label, pin_keycode),
-                    KeypinEvent::Up => MatrixEvent::KeyUp(pin_label, pin_keycode),
-                };
-                return Poll::Ready(Some(matrix_event));
-            }
+    // Read the keys and feed them to their debouncers
+    fn update(&mut self, now: Instant);
+}
+
+// The next event of a sampled scanner. Everything the last read found is
+// returned, oldest first, before reading again.
+pub fn poll_sampled<S: Sampled>(
+    scanner: &mut S,
+    cx: &mut Context<'_>,
+) -> Poll<Option<MatrixEvent>> {
+    loop {
+        if let Some((slot, at)) = scanner.pending().min_by_key(|(_, at)| *at) {
+            let at = scanner.sampler().oldest.clamp(at);
+            let Some((position, down)) = scanner.take(slot) else {
+                continue;
+            };
+            return Poll::Ready(Some(MatrixEvent::new(position, down, at)));
         }
 
-        Poll::Pending
+        let sampler = scanner.sampler();
+        if core::pin::Pin::new(&mut sampler.next_scan)
+            .poll(cx)
+            .is_pending()
+        {
+            return Poll::Pending;
+        }
+        let now = sampler.clock.now();
+        sampler.next_scan = sampler.clock.at(now + SCAN_INTERVAL);
+        scanner.update(now);
     }
 }
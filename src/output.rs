@@ -0,0 +1,359 @@
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::Channel;
+use usbd_hid::descriptor::KeyboardReport;
+
+const REPORT_CHANNEL_CAPACITY: usize = 256;
+const HISTORY_LEN: usize = 64;
+// Reports one character can take: WinCompose spends 18 on a character
+// outside the Basic Multilingual Plane
+const MAX_SEQUENCE: usize = 18;
+
+pub const MODIFIER_LEFT_CTRL: u8 = 0x01;
+pub const MODIFIER_LEFT_SHIFT: u8 = 0x02;
+pub const MODIFIER_LEFT_ALT: u8 = 0x04;
+pub const MODIFIER_RIGHT_ALT: u8 = 0x40;
+
+const KEYPAD_PLUS: u8 = 0x57;
+
+// How characters without a plain HID usage are entered on the host
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Unicode {
+    // Option + hex code with the "Unicode Hex Input" input source
+    MacOs,
+    // ctrl+shift+u, hex code, space (IBus and GTK)
+    Linux,
+    // Compose (right alt), u, hex code, enter
+    WinCompose,
+    // Alt + keypad plus + hex code, with EnableHexNumpad set in the registry
+    AltCodes,
+}
+
+pub const DEFAULT_UNICODE: Unicode = Unicode::MacOs;
+
+// Reports are queued here by `Output` and drained by the HID writer in
+// `run_primary`, so key handling never has to await the USB endpoint.
+pub static REPORT_CHANNEL: Channel<ThreadModeRawMutex, KeyboardReport, REPORT_CHANNEL_CAPACITY> =
+    Channel::new();
+
+/// Map a character to its (modifier, usage) pair on a US layout.
+pub fn usage(c: char) -> Option<(u8, u8)> {
+    let shift = MODIFIER_LEFT_SHIFT;
+    Some(match c {
+        'a'..='z' => (0, (c as u8) - b'a' + 0x04),
+        'A'..='Z' => (shift, (c as u8) - b'A' + 0x04),
+        '1'..='9' => (0, (c as u8) - b'1' + 0x1e),
+        '0' => (0, 0x27),
+        '\n' => (0, 0x28),
+        '\x1b' => (0, 0x29),
+        '\x08' => (0, 0x2a),
+        '\t' => (0, 0x2b),
+        ' ' => (0, 0x2c),
+        '-' => (0, 0x2d),
+        '=' => (0, 0x2e),
+        '[' => (0, 0x2f),
+        ']' => (0, 0x30),
+        '\\' => (0, 0x31),
+        ';' => (0, 0x33),
+        '\'' => (0, 0x34),
+        '`' => (0, 0x35),
+        ',' => (0, 0x36),
+        '.' => (0, 0x37),
+        '/' => (0, 0x38),
+        '!' => (shift, 0x1e),
+        '@' => (shift, 0x1f),
+        '#' => (shift, 0x20),
+        '$' => (shift, 0x21),
+        '%' => (shift, 0x22),
+        '^' => (shift, 0x23),
+        '&' => (shift, 0x24),
+        '*' => (shift, 0x25),
+        '(' => (shift, 0x26),
+        ')' => (shift, 0x27),
+        '_' => (shift, 0x2d),
+        '+' => (shift, 0x2e),
+        '{' => (shift, 0x2f),
+        '}' => (shift, 0x30),
+        '|' => (shift, 0x31),
+        ':' => (shift, 0x33),
+        '"' => (shift, 0x34),
+        '~' => (shift, 0x35),
+        '<' => (shift, 0x36),
+        '>' => (shift, 0x37),
+        '?' => (shift, 0x38),
+        _ => return None,
+    })
+}
+
+fn hex_digit(value: u32) -> char {
+    match value {
+        0..=9 => (b'0' + value as u8) as char,
+        _ => (b'a' + (value - 10) as u8) as char,
+    }
+}
+
+// Alt codes only accept decimal digits from the keypad
+fn keypad_usage(c: char) -> Option<u8> {
+    match c {
+        '1'..='9' => Some((c as u8) - b'1' + 0x59),
+        '0' => Some(0x62),
+        _ => None,
+    }
+}
+
+// Tail of what has been typed on screen, as far as the keyboard knows
+struct History {
+    chars: [char; HISTORY_LEN],
+    len: usize,
+}
+
+impl History {
+    const fn new() -> Self {
+        Self {
+            chars: ['\0'; HISTORY_LEN],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, c: char) {
+        if self.len == HISTORY_LEN {
+            self.chars.copy_within(1.., 0);
+            self.len -= 1;
+        }
+        self.chars[self.len] = c;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<char> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.chars[self.len])
+    }
+
+    fn last(&self) -> Option<char> {
+        self.chars[..self.len].last().copied()
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+pub struct Output {
+    unicode: Unicode,
+    history: History,
+    // Number of characters at the end of `history` produced by the last
+    // chord, or None once anything else has been typed. Edits attributed to
+    // the chord keep this exact so undo removes precisely what is on screen.
+    chord: Option<usize>,
+    // Whether taps are currently being attributed to the last chord
+    recording: bool,
+    // Reports of the character being typed, queued together once it is
+    // complete so a full queue never splits a press from its release
+    pending: [(u8, u8); MAX_SEQUENCE],
+    pending_len: usize,
+}
+
+impl Output {
+    pub fn new(unicode: Unicode) -> Self {
+        Self {
+            unicode,
+            history: History::new(),
+            chord: None,
+            recording: false,
+            pending: [(0, 0); MAX_SEQUENCE],
+            pending_len: 0,
+        }
+    }
+
+    fn send(&mut self, modifier: u8, keycode: u8) {
+        if self.pending_len < MAX_SEQUENCE {
+            self.pending[self.pending_len] = (modifier, keycode);
+            self.pending_len += 1;
+        }
+    }
+
+    // Queue the pending reports if they all fit, otherwise drop the whole
+    // character so the host is never left with a key held. Returns whether
+    // it was typed.
+    fn flush(&mut self) -> bool {
+        let len = core::mem::take(&mut self.pending_len);
+        if REPORT_CHANNEL.free_capacity() < len {
+            let _ = crate::SERIAL_CHANNEL.try_send("Report queue full, key dropped\r\n");
+            return false;
+        }
+        for &(modifier, keycode) in &self.pending[..len] {
+            let _ = REPORT_CHANNEL.try_send(KeyboardReport {
+                modifier,
+                reserved: 0,
+                leds: 0,
+                keycodes: [keycode, 0, 0, 0, 0, 0],
+            });
+        }
+        true
+    }
+
+    fn record(&mut self, c: char) {
+        if c == '\x08' {
+            self.history.pop();
+        } else {
+            self.history.push(c);
+        }
+
+        self.chord = match self.chord {
+            Some(len) if self.recording => Some(if c == '\x08' {
+                len.saturating_sub(1)
+            } else {
+                len + 1
+            }),
+            _ => None,
+        };
+    }
+
+    // Type the hex digits of `value`, at least four, with `modifier` held
+    // throughout
+    fn hex(&mut self, modifier: u8, value: u32) {
+        let digits = (8 - value.leading_zeros() as usize / 4).max(4);
+        for i in (0..digits).rev() {
+            let digit = hex_digit((value >> (i * 4)) & 0xf);
+            let keycode = match self.unicode {
+                Unicode::AltCodes => keypad_usage(digit).or_else(|| usage(digit).map(|(_, k)| k)),
+                _ => usage(digit).map(|(_, k)| k),
+            };
+            if let Some(keycode) = keycode {
+                self.send(modifier, keycode);
+                self.send(modifier, 0);
+            }
+        }
+    }
+
+    fn unicode(&mut self, c: char) {
+        match self.unicode {
+            Unicode::MacOs => {
+                // Unicode Hex Input takes UTF-16 code units
+                let mut units = [0u16; 2];
+                for &unit in c.encode_utf16(&mut units).iter() {
+                    self.hex(MODIFIER_LEFT_ALT, unit as u32);
+                }
+                self.send(0, 0);
+            }
+            Unicode::Linux => {
+                if let Some((_, u)) = usage('u') {
+                    self.send(MODIFIER_LEFT_CTRL | MODIFIER_LEFT_SHIFT, u);
+                    self.send(0, 0);
+                }
+                self.hex(0, c as u32);
+                self.send(0, 0x2c);
+                self.send(0, 0);
+            }
+            Unicode::WinCompose => {
+                self.send(MODIFIER_RIGHT_ALT, 0);
+                self.send(0, 0);
+                if let Some((_, u)) = usage('u') {
+                    self.send(0, u);
+                    self.send(0, 0);
+                }
+                self.hex(0, c as u32);
+                self.send(0, 0x28);
+                self.send(0, 0);
+            }
+            Unicode::AltCodes => {
+                self.send(MODIFIER_LEFT_ALT, KEYPAD_PLUS);
+                self.send(MODIFIER_LEFT_ALT, 0);
+                self.hex(MODIFIER_LEFT_ALT, c as u32);
+                self.send(0, 0);
+            }
+        }
+    }
+
+    /// Press and release the key that types `c`, falling back to the host's
+    /// Unicode input method for characters without a HID usage.
+    pub fn tap(&mut self, c: char) {
+        if let Some((modifier, keycode)) = usage(c) {
+            self.send(modifier, keycode);
+            self.send(0, 0);
+        } else if c.is_ascii_control() {
+            return;
+        } else {
+            self.unicode(c);
+        }
+        // Only what reached the host counts, so undo stays exact
+        if self.flush() {
+            self.record(c);
+        }
+    }
+
+    /// Tap `c` with extra modifiers held. The effect on screen is unknown,
+    /// so history is discarded.
+    pub fn tap_modified(&mut self, extra: u8, c: char) {
+        if let Some((modifier, keycode)) = usage(c) {
+            self.send(modifier | extra, keycode);
+            self.send(0, 0);
+            if self.flush() {
+                self.history.clear();
+                self.chord = None;
+            }
+        }
+    }
+
+    pub fn text(&mut self, text: &str) {
+        for c in text.chars() {
+            self.tap(c);
+        }
+    }
+
+    /// Type `text` with its first letter uppercased.
+    pub fn capitalized(&mut self, text: &str) {
+        let mut chars = text.chars();
+        if let Some(first) = chars.next() {
+            self.tap(first.to_ascii_uppercase());
+        }
+        for c in chars {
+            self.tap(c);
+        }
+    }
+
+    pub fn backspace(&mut self, count: usize) {
+        for _ in 0..count {
+            self.tap('\x08');
+        }
+    }
+
+    /// Start attributing taps to a new chord's output.
+    pub fn chord_start(&mut self) {
+        self.chord = Some(0);
+        self.recording = true;
+    }
+
+    /// Resume attributing taps to the last chord, for edits such as smart
+    /// punctuation.
+    pub fn chord_resume(&mut self) {
+        self.recording = self.chord.is_some();
+    }
+
+    pub fn chord_end(&mut self) {
+        self.recording = false;
+    }
+
+    /// Whether the last thing on screen is the space a chord typed after its
+    /// text.
+    pub fn ends_with_chord_space(&self) -> bool {
+        self.chord.is_some_and(|len| len > 0) && self.history.last() == Some(' ')
+    }
+
+    /// Erase exactly the last chord's output. Returns false if something
+    /// else has been typed since the chord.
+    pub fn undo_chord(&mut self) -> bool {
+        match self.chord {
+            Some(len) => {
+                self.recording = true;
+                self.backspace(len);
+                self.recording = false;
+                self.chord = None;
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,193 @@
+pub const COLS: u8 = 5;
+pub const THUMBS: u8 = 2;
+pub const KEYS_PER_HAND: u8 = 3 * COLS + THUMBS;
+
+// Which half of the board, as stored in the stash
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Hand {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Row {
+    Top,
+    Home,
+    Bottom,
+    Thumb,
+}
+
+// How many keys a row has on each hand
+pub const fn cols(row: Row) -> u8 {
+    match row {
+        Row::Thumb => THUMBS,
+        _ => COLS,
+    }
+}
+
+// Where a key physically sits, whatever pin it is wired to. Columns count
+// from 1 at the outer edge of each hand, so a position and its mirror on
+// the other hand differ only in `hand`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct KeyPosition {
+    pub hand: Hand,
+    pub row: Row,
+    pub col: u8,
+    // Counts across the whole board, the left hand first
+    pub index: u8,
+}
+
+impl KeyPosition {
+    pub const fn new(hand: Hand, row: Row, col: u8) -> Self {
+        let base = match hand {
+            Hand::Left => 0,
+            Hand::Right => KEYS_PER_HAND,
+        };
+        let row_start = match row {
+            Row::Top => 0,
+            Row::Home => COLS,
+            Row::Bottom => 2 * COLS,
+            Row::Thumb => 3 * COLS,
+        };
+        Self {
+            hand,
+            row,
+            col,
+            index: base + row_start + col - 1,
+        }
+    }
+
+    // The position with the given index, as sent between the halves
+    pub fn from_index(index: u8) -> Option<Self> {
+        let (hand, key) = match index {
+            i if i < KEYS_PER_HAND => (Hand::Left, i),
+            i if i < 2 * KEYS_PER_HAND => (Hand::Right, i - KEYS_PER_HAND),
+            _ => return None,
+        };
+        let (row, col) = match key / COLS {
+            0 => (Row::Top, key),
+            1 => (Row::Home, key - COLS),
+            2 => (Row::Bottom, key - 2 * COLS),
+            _ => (Row::Thumb, key - 3 * COLS),
+        };
+        Some(Self::new(hand, row, col + 1))
+    }
+
+    // Send the position's name, like L_TOP_3 or R_THUMB_1, on the
+    // sidechannel
+    pub fn send_name(&self) {
+        const DIGITS: [&str; 6] = ["0", "1", "2", "3", "4", "5"];
+        let _ = crate::SERIAL_CHANNEL.try_send(match self.hand {
+            Hand::Left => "L_",
+            Hand::Right => "R_",
+        });
+        let _ = crate::SERIAL_CHANNEL.try_send(match self.row {
+            Row::Top => "TOP_",
+            Row::Home => "HOME_",
+            Row::Bottom => "BOTTOM_",
+            Row::Thumb => "THUMB_",
+        });
+        let _ = crate::SERIAL_CHANNEL.try_send(DIGITS[self.col as usize]);
+    }
+}
+
+// The row and column of the key on each GPIO, for keys wired one per pin. The Sweep's PCB is
+// reversible, so both halves are wired alike as seen from their outer edge.
+#[cfg(not(feature = "matrix-scan"))]
+const SWEEP: &[(u8, Row, u8)] = &[
+    (7, Row::Top, 1),
+    (26, Row::Top, 2),
+    (27, Row::Top, 3),
+    (28, Row::Top, 4),
+    (29, Row::Top, 5),
+    (22, Row::Home, 1),
+    (20, Row::Home, 2),
+    (23, Row::Home, 3),
+    (21, Row::Home, 4),
+    (0, Row::Home, 5),
+    (2, Row::Bottom, 1),
+    (3, Row::Bottom, 2),
+    (4, Row::Bottom, 3),
+    (5, Row::Bottom, 4),
+    (6, Row::Bottom, 5),
+    (8, Row::Thumb, 1),
+    (9, Row::Thumb, 2),
+];
+
+// Checks that each key has exactly one pin: no pin is used twice or clashes
+// with sync, every key is inside the layout and there are as many pins as
+// keys
+#[cfg(not(feature = "matrix-scan"))]
+const _: () = {
+    // GP1 carries sync between the halves
+    const SYNC_PIN: u8 = 1;
+    const GPIOS: u8 = 30;
+
+    let mut i = 0;
+    while i < SWEEP.len() {
+        let (gpio, row, col) = SWEEP[i];
+        assert!(gpio < GPIOS && gpio != SYNC_PIN, "Key on an unusable pin");
+        assert!(col >= 1 && col <= cols(row), "Key outside the layout");
+        let mut j = i + 1;
+        while j < SWEEP.len() {
+            assert!(SWEEP[j].0 != gpio, "Pin wired to two keys");
+            assert!(
+                SWEEP[j].1 as u8 != row as u8 || SWEEP[j].2 != col,
+                "Key wired to two pins"
+            );
+            j += 1;
+        }
+        i += 1;
+    }
+    assert!(SWEEP.len() == KEYS_PER_HAND as usize, "Key without a pin");
+};
+
+#[cfg(not(feature = "matrix-scan"))]
+pub fn wiring(hand: Hand) -> &'static [(u8, Row, u8)] {
+    match hand {
+        Hand::Left => SWEEP,
+        Hand::Right => SWEEP,
+    }
+}
+
+// Whether `pins`, named as embassy-rp names them, are the GPIOs of the
+// wiring in its order, so main.rs cannot claim pins the wiring does not list
+#[cfg(not(feature = "matrix-scan"))]
+pub const fn is_sweep_order(pins: &[&str]) -> bool {
+    if pins.len() != SWEEP.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < pins.len() {
+        let name = pins[i].as_bytes();
+        if name.len() < 5
+            || name[0] != b'P'
+            || name[1] != b'I'
+            || name[2] != b'N'
+            || name[3] != b'_'
+        {
+            return false;
+        }
+        let mut gpio = 0u32;
+        let mut j = 4;
+        while j < name.len() {
+            if !name[j].is_ascii_digit() {
+                return false;
+            }
+            gpio = gpio * 10 + (name[j] - b'0') as u32;
+            j += 1;
+        }
+        if gpio != SWEEP[i].0 as u32 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// The key on the `i`th pin of the wiring
+#[cfg(not(feature = "matrix-scan"))]
+pub fn wired(hand: Hand, i: usize) -> KeyPosition {
+    let (_, row, col) = wiring(hand)[i];
+    KeyPosition::new(hand, row, col)
+}
@@ -0,0 +1,188 @@
+// Scanning backend for diode row/column matrices, for boards that do not
+// give every key its own pin. Yields the same events as `Matrix`.
+use crate::clock::Clock;
+use crate::debounce::{self, Debounce, Debouncer};
+use crate::matrix::{self, MatrixEvent, Sampled, Sampler};
+use crate::position::KeyPosition;
+use core::task::Poll;
+use embassy_rp::gpio::{Flex, Pull};
+use embassy_time::{Duration, Instant};
+use futures_core::Stream;
+
+// Long enough for the sense lines to follow a driven line through the
+// diodes and pull-ups on most boards
+pub const DEFAULT_SETTLE: Duration = Duration::from_micros(30);
+
+// Which way the diodes point, named as in QMK
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Diodes {
+    // Cathodes towards the rows: each row is driven low in turn and the
+    // columns are read
+    Col2Row,
+    // Cathodes towards the columns: each column is driven low in turn and
+    // the rows are read
+    Row2Col,
+}
+
+pub struct ScanMatrix<const R: usize, const C: usize, K: Clock> {
+    rows: [Flex<'static>; R],
+    cols: [Flex<'static>; C],
+    diodes: Diodes,
+    // How long a driven line is given before the others are read
+    settle: Duration,
+    // None where no switch sits at a crossing
+    keys: [[Option<KeyPosition>; C]; R],
+    debouncers: [[Debouncer; C]; R],
+    // Levels read by the last scan
+    raw: [[bool; C]; R],
+    // Levels reported by the debouncers but not yet returned
+    pending: [[Option<bool>; C]; R],
+    sampler: Sampler<K>,
+    ghosting: bool,
+}
+
+impl<const R: usize, const C: usize, K: Clock> ScanMatrix<R, C, K> {
+    pub fn new(
+        clock: K,
+        mut rows: [Flex<'static>; R],
+        mut cols: [Flex<'static>; C],
+        diodes: Diodes,
+        settle: Duration,
+        debounce: Debounce,
+        keys: [[Option<KeyPosition>; C]; R],
+    ) -> Self {
+        let (drive, sense) = match diodes {
+            Diodes::Col2Row => (&mut rows[..], &mut cols[..]),
+            Diodes::Row2Col => (&mut cols[..], &mut rows[..]),
+        };
+        for line in drive {
+            line.set_high();
+            line.set_as_output();
+        }
+        for line in sense {
+            line.set_as_input();
+            line.set_pull(Pull::Up);
+        }
+
+        Self {
+            rows,
+            cols,
+            diodes,
+            settle,
+            keys,
+            debouncers: core::array::from_fn(|r| {
+                core::array::from_fn(|c| Debouncer::new(debounce::for_key(keys[r][c], debounce)))
+            }),
+            raw: [[false; C]; R],
+            pending: [[None; C]; R],
+            sampler: Sampler::new(clock),
+            ghosting: false,
+        }
+    }
+
+    // Drive each line low in turn and read which keys connect it to the
+    // lines across
+    fn scan(&mut self) -> [[bool; C]; R] {
+        let mut down = [[false; C]; R];
+        match self.diodes {
+            Diodes::Col2Row => {
+                for (r, row) in self.rows.iter_mut().enumerate() {
+                    row.set_low();
+                    embassy_time::block_for(self.settle);
+                    for (c, col) in self.cols.iter().enumerate() {
+                        down[r][c] = col.is_low();
+                    }
+                    row.set_high();
+                }
+            }
+            Diodes::Row2Col => {
+                for (c, col) in self.cols.iter_mut().enumerate() {
+                    col.set_low();
+                    embassy_time::block_for(self.settle);
+                    for (r, row) in self.rows.iter().enumerate() {
+                        down[r][c] = row.is_low();
+                    }
+                    col.set_high();
+                }
+            }
+        }
+        down
+    }
+
+    // Three keys on the corners of a rectangle close the fourth corner too
+    // when a diode is missing or shorted, so a scan where two rows share two
+    // pressed columns cannot be trusted for either row
+    fn ghosted(down: &[[bool; C]; R], r: usize) -> bool {
+        (0..R).any(|other| {
+            other != r
+                && down[r]
+                    .iter()
+                    .zip(&down[other])
+                    .filter(|(a, b)| **a && **b)
+                    .count()
+                    >= 2
+        })
+    }
+}
+
+impl<const R: usize, const C: usize, K: Clock> Sampled for ScanMatrix<R, C, K> {
+    type Clock = K;
+
+    fn sampler(&mut self) -> &mut Sampler<K> {
+        &mut self.sampler
+    }
+
+    fn pending(&self) -> impl Iterator<Item = (usize, Instant)> {
+        (0..R * C)
+            .map(|i| (i, (i / C, i % C)))
+            .filter(|&(_, (r, c))| self.pending[r][c].is_some())
+            .map(|(i, (r, c))| (i, self.debouncers[r][c].changed()))
+    }
+
+    fn take(&mut self, slot: usize) -> Option<(KeyPosition, bool)> {
+        let (r, c) = (slot / C, slot % C);
+        let down = self.pending[r][c].take() == Some(true);
+        Some((self.keys[r][c]?, down))
+    }
+
+    fn update(&mut self, now: Instant) {
+        let scanned = self.scan();
+
+        let mut down = scanned;
+        let mut ghosting = false;
+        for (r, row) in down.iter_mut().enumerate() {
+            if Self::ghosted(&scanned, r) {
+                // Keep the row as it was until the ambiguity clears
+                *row = self.raw[r];
+                ghosting = true;
+            }
+        }
+        if ghosting && !self.ghosting {
+            let _ = crate::SERIAL_CHANNEL.try_send("Ghosting detected, ignoring affected rows\r\n");
+        }
+        self.ghosting = ghosting;
+
+        for (r, row) in down.iter().enumerate() {
+            for (c, &level) in row.iter().enumerate() {
+                let debouncer = &mut self.debouncers[r][c];
+                self.pending[r][c] = if level != self.raw[r][c] {
+                    debouncer.edge(level, now)
+                } else {
+                    debouncer.settle(now)
+                };
+            }
+        }
+        self.raw = down;
+    }
+}
+
+impl<const R: usize, const C: usize, K: Clock> Stream for ScanMatrix<R, C, K> {
+    type Item = MatrixEvent;
+
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        matrix::poll_sampled(self.get_mut(), cx)
+    }
+}
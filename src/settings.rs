@@ -0,0 +1,364 @@
+use crate::debounce::{self, Algorithm};
+use crate::hard::{self, Double};
+use crate::matrix::{self, Scanner};
+use crate::output::{self, Unicode};
+use crate::position::Hand;
+use core::ops::RangeInclusive;
+
+const DEFAULT_TAPPING_TERM_MS: u16 = 200;
+const DEFAULT_CHORD_TERM_MS: u16 = 50;
+const DEFAULT_DEBOUNCE_MS: u16 = 15;
+
+const TAPPING_TERM_RANGE: RangeInclusive<u32> = 50..=1000;
+const CHORD_TERM_RANGE: RangeInclusive<u32> = 10..=200;
+const DEBOUNCE_RANGE: RangeInclusive<u32> = 1..=50;
+
+// Most values `Settings` holds, counting ids saved by newer firmware
+pub const MAX_VALUES: usize = 32;
+
+// How much the sidechannel reports
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    // Errors only
+    Quiet,
+    // Chords, hard mode and setting changes
+    Normal,
+    // Also every key up and down
+    Verbose,
+}
+
+// A setting's value, as stored in the stash and as typed on the console
+pub trait Value: Copy {
+    fn encode(&self) -> u32;
+    fn decode(raw: u32) -> Result<Self, &'static str>;
+    fn parse(text: &str) -> Result<Self, &'static str>;
+    // Send the value on the sidechannel the way `parse` takes it
+    fn send(&self);
+}
+
+// A value picked from a list, each with the code it is stored as and its
+// name on the console. The list covers every value, and codes are never
+// reused since old entries may still be stored under them.
+pub trait Choice: Copy + PartialEq + 'static {
+    const CHOICES: &'static [(Self, u32, &'static str)];
+    // Errors for an unknown name and an unknown code
+    const EXPECTED: &'static str;
+    const INVALID: &'static str;
+}
+
+impl<T: Choice> Value for T {
+    fn encode(&self) -> u32 {
+        T::CHOICES
+            .iter()
+            .find(|(value, _, _)| value == self)
+            .map_or(0, |&(_, code, _)| code)
+    }
+
+    fn decode(raw: u32) -> Result<Self, &'static str> {
+        T::CHOICES
+            .iter()
+            .find(|&&(_, code, _)| code == raw)
+            .map(|&(value, _, _)| value)
+            .ok_or(T::INVALID)
+    }
+
+    fn parse(text: &str) -> Result<Self, &'static str> {
+        T::CHOICES
+            .iter()
+            .find(|&&(_, _, name)| name == text)
+            .map(|&(value, _, _)| value)
+            .ok_or(T::EXPECTED)
+    }
+
+    fn send(&self) {
+        if let Some(&(_, _, name)) = T::CHOICES.iter().find(|(value, _, _)| value == self) {
+            let _ = crate::SERIAL_CHANNEL.try_send(name);
+        }
+    }
+}
+
+impl Choice for Hand {
+    const CHOICES: &'static [(Self, u32, &'static str)] =
+        &[(Hand::Left, 0, "left"), (Hand::Right, 1, "right")];
+    const EXPECTED: &'static str = "Expected left or right";
+    const INVALID: &'static str = "Invalid hand";
+}
+
+impl Choice for Unicode {
+    const CHOICES: &'static [(Self, u32, &'static str)] = &[
+        (Unicode::MacOs, 1, "macos"),
+        (Unicode::Linux, 2, "linux"),
+        (Unicode::WinCompose, 3, "wincompose"),
+        (Unicode::AltCodes, 4, "altcodes"),
+    ];
+    const EXPECTED: &'static str = "Expected macos, linux, wincompose or altcodes";
+    const INVALID: &'static str = "Invalid unicode mode";
+}
+
+impl Choice for bool {
+    const CHOICES: &'static [(Self, u32, &'static str)] = &[(false, 1, "off"), (true, 2, "on")];
+    const EXPECTED: &'static str = "Expected on or off";
+    const INVALID: &'static str = "Invalid flag";
+}
+
+impl Choice for Double {
+    const CHOICES: &'static [(Self, u32, &'static str)] = &[
+        (Double::Swallow, 1, "swallow"),
+        (Double::Backspace, 2, "backspace"),
+        (Double::Warn, 3, "warn"),
+    ];
+    const EXPECTED: &'static str = "Expected swallow, backspace or warn";
+    const INVALID: &'static str = "Invalid double letters";
+}
+
+impl Choice for Verbosity {
+    const CHOICES: &'static [(Self, u32, &'static str)] = &[
+        (Verbosity::Quiet, 1, "quiet"),
+        (Verbosity::Normal, 2, "normal"),
+        (Verbosity::Verbose, 3, "verbose"),
+    ];
+    const EXPECTED: &'static str = "Expected quiet, normal or verbose";
+    const INVALID: &'static str = "Invalid verbosity";
+}
+
+impl Choice for Algorithm {
+    const CHOICES: &'static [(Self, u32, &'static str)] = &[
+        (Algorithm::Eager, 1, "eager"),
+        (Algorithm::Deferred, 2, "deferred"),
+        (Algorithm::Asymmetric, 3, "asymmetric"),
+    ];
+    const EXPECTED: &'static str = "Expected eager, deferred or asymmetric";
+    const INVALID: &'static str = "Invalid debounce algorithm";
+}
+
+impl Choice for Scanner {
+    const CHOICES: &'static [(Self, u32, &'static str)] = &[
+        (Scanner::Interrupt, 1, "interrupt"),
+        (Scanner::Bank, 2, "bank"),
+    ];
+    const EXPECTED: &'static str = "Expected interrupt or bank";
+    const INVALID: &'static str = "Invalid scanner";
+}
+
+impl Value for u16 {
+    fn encode(&self) -> u32 {
+        *self as u32
+    }
+
+    fn decode(raw: u32) -> Result<Self, &'static str> {
+        u16::try_from(raw).map_err(|_| "Value out of range")
+    }
+
+    fn parse(text: &str) -> Result<Self, &'static str> {
+        text.parse().map_err(|_| "Expected a number")
+    }
+
+    fn send(&self) {
+        send_decimal(*self as u32);
+    }
+}
+
+// Names a setting, the type of its value and the value it has until set
+#[derive(Clone, Copy)]
+pub struct Key<T> {
+    pub id: u8,
+    name: &'static str,
+    default: T,
+    // Stored values outside this are rejected
+    range: (u32, u32),
+}
+
+impl<T: Copy> Key<T> {
+    const fn new(id: u8, name: &'static str, default: T) -> Self {
+        Self {
+            id,
+            name,
+            default,
+            range: (0, u32::MAX),
+        }
+    }
+
+    const fn within(self, range: RangeInclusive<u32>) -> Self {
+        Self {
+            range: (*range.start(), *range.end()),
+            ..self
+        }
+    }
+
+    fn check(&self, raw: u32) -> Result<u32, &'static str> {
+        if raw < self.range.0 || raw > self.range.1 {
+            return Err("Value out of range");
+        }
+        Ok(raw)
+    }
+}
+
+// Declares every setting's key from one table, along with the lookups the
+// console and the stash make by name and by id
+macro_rules! keys {
+    ($($key:ident: $value:ty = ($id:literal, $name:literal, $default:expr $(, $range:expr)?),)*) => {
+        $(pub const $key: Key<$value> = Key::new($id, $name, $default)$(.within($range))?;)*
+
+        // Names used on the console and in reports
+        pub const NAMES: &[&str] = &[$($key.name),*];
+
+        const IDS: &[u8] = &[$($id),*];
+
+        // Unknown ids are accepted as they are, as newer firmware may have
+        // saved them
+        fn check(id: u8, raw: u32) -> Result<(), &'static str> {
+            $(if id == $id {
+                <$value>::decode(raw)?;
+                $key.check(raw)?;
+            })*
+            Ok(())
+        }
+
+        impl Settings {
+            // Change a setting by name, as typed on the console
+            pub fn set_named(&mut self, name: &str, text: &str) -> Result<(), &'static str> {
+                match name {
+                    $($name => self.set($key, <$value>::parse(text)?),)*
+                    _ => Err("Unknown setting"),
+                }
+            }
+
+            fn send_named(&self, name: &str) -> Result<(), &'static str> {
+                match name {
+                    $($name => self.get($key).send(),)*
+                    _ => return Err("Unknown setting"),
+                }
+                Ok(())
+            }
+        }
+    };
+}
+
+// Every setting. Features read theirs with `Settings::get`. New keys take a
+// fresh id; ids of removed keys are never reused, since old entries may
+// still be stored under them.
+keys! {
+    HAND: Hand = (1, "hand", Hand::Left),
+    UNICODE: Unicode = (2, "unicode", output::DEFAULT_UNICODE),
+    HARD_MODE: bool = (3, "hard_mode", hard::DEFAULT_HARD_MODE),
+    DOUBLE_LETTERS: Double = (4, "double_letters", hard::DEFAULT_DOUBLE_LETTERS),
+    VERBOSITY: Verbosity = (5, "verbosity", Verbosity::Verbose),
+    TAPPING_TERM_MS: u16 = (6, "tapping_term_ms", DEFAULT_TAPPING_TERM_MS, TAPPING_TERM_RANGE),
+    CHORD_TERM_MS: u16 = (7, "chord_term_ms", DEFAULT_CHORD_TERM_MS, CHORD_TERM_RANGE),
+    DEBOUNCE_ALGORITHM: Algorithm = (9, "debounce_algorithm", debounce::DEFAULT_ALGORITHM),
+    DEBOUNCE_PRESS_MS: u16 = (8, "debounce_press_ms", DEFAULT_DEBOUNCE_MS, DEBOUNCE_RANGE),
+    DEBOUNCE_RELEASE_MS: u16 = (11, "debounce_release_ms", DEFAULT_DEBOUNCE_MS, DEBOUNCE_RANGE),
+    SCANNER: Scanner = (10, "scanner", matrix::DEFAULT_SCANNER),
+}
+
+const _: () = {
+    assert!(IDS.len() <= MAX_VALUES, "Too many settings");
+    let mut i = 0;
+    while i < IDS.len() {
+        let mut j = i + 1;
+        while j < IDS.len() {
+            assert!(IDS[i] != IDS[j], "Duplicate setting id");
+            j += 1;
+        }
+        i += 1;
+    }
+};
+
+// Setting values by key id. Settings that were never set have their key's
+// default, and ids this firmware does not know are kept so the stash writes
+// them back untouched.
+#[derive(Clone)]
+pub struct Settings {
+    ids: [u8; MAX_VALUES],
+    values: [u32; MAX_VALUES],
+    len: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            ids: [0; MAX_VALUES],
+            values: [0; MAX_VALUES],
+            len: 0,
+        }
+    }
+}
+
+impl Settings {
+    pub fn get<T: Value>(&self, key: Key<T>) -> T {
+        // Values are checked as they are stored, so this only falls back to
+        // the default for settings that were never set
+        self.raw(key.id)
+            .and_then(|raw| T::decode(raw).ok())
+            .unwrap_or(key.default)
+    }
+
+    pub fn set<T: Value>(&mut self, key: Key<T>, value: T) -> Result<(), &'static str> {
+        let raw = key.check(value.encode())?;
+        self.set_raw(key.id, raw)
+    }
+
+    pub fn raw(&self, id: u8) -> Option<u32> {
+        let i = self.ids[..self.len].iter().position(|&other| other == id)?;
+        Some(self.values[i])
+    }
+
+    // Store a value as saved in the stash, checked if its key is known
+    pub fn set_raw(&mut self, id: u8, raw: u32) -> Result<(), &'static str> {
+        check(id, raw)?;
+        let i = match self.ids[..self.len].iter().position(|&other| other == id) {
+            Some(i) => i,
+            None if self.len < MAX_VALUES => {
+                self.len += 1;
+                self.len - 1
+            }
+            None => return Err("Too many settings"),
+        };
+        self.ids[i] = id;
+        self.values[i] = raw;
+        Ok(())
+    }
+
+    // Every stored id and value, known or not
+    pub fn iter_raw(&self) -> impl Iterator<Item = (u8, u32)> + '_ {
+        self.ids[..self.len]
+            .iter()
+            .copied()
+            .zip(self.values[..self.len].iter().copied())
+    }
+
+    // Report one setting on the sidechannel as "Setting name=value"
+    pub fn report_setting(&self, name: &str) -> Result<(), &'static str> {
+        let name = NAMES
+            .iter()
+            .copied()
+            .find(|&known| known == name)
+            .ok_or("Unknown setting")?;
+
+        let _ = crate::SERIAL_CHANNEL.try_send("Setting ");
+        let _ = crate::SERIAL_CHANNEL.try_send(name);
+        let _ = crate::SERIAL_CHANNEL.try_send("=");
+        self.send_named(name)?;
+        let _ = crate::SERIAL_CHANNEL.try_send("\r\n");
+        Ok(())
+    }
+
+    // Report every setting on the sidechannel
+    pub fn report(&self) {
+        for name in NAMES {
+            let _ = self.report_setting(name);
+        }
+    }
+}
+
+pub fn send_decimal(value: u32) {
+    const DIGITS: [&str; 10] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+    let mut divisor = 1_000_000_000;
+    while divisor > 1 && value / divisor == 0 {
+        divisor /= 10;
+    }
+    while divisor > 0 {
+        let _ = crate::SERIAL_CHANNEL.try_send(DIGITS[(value / divisor % 10) as usize]);
+        divisor /= 10;
+    }
+}
@@ -1,113 +1,204 @@
-use embassy_rp::flash::{Blocking, Flash};
-use embassy_rp::{Peri, peripherals::FLASH};
+use crate::journal::{self, Journal, SECTOR_SIZE, SECTORS};
+use crate::settings::{self, Key, Settings, Value};
+use embassy_rp::Peri;
+use embassy_rp::flash::{Async, Flash};
+use embassy_rp::peripherals::{DMA_CH0, FLASH};
 
-const XIP_BASE: u32 = 0x10000000;
-const CONFIG_OFFSET: u32 = 0x001FF000;
-const MAGIC: u32 = 0x1113_0001;
 const FLASH_SIZE: usize = 2 * 1024 * 1024;
+// The CONFIG region in memory.x
+const CONFIG_OFFSET: u32 = FLASH_SIZE as u32 - SECTORS * SECTOR_SIZE;
+const ERASED: u32 = 0xFFFF_FFFF;
+
+// The baseline firmware kept only the hand, in a single record at the start
+// of the last sector that was erased and rewritten on every save. It counts
+// as version 1.
+const LEGACY_OFFSET: u32 = (SECTORS - 1) * SECTOR_SIZE;
+const LEGACY_MAGIC: u32 = 0x1113_0001;
+// Journal records of key-value entries
+const VERSION: u32 = 2;
+
+// Key id followed by the value as a little-endian u32
+const ENTRY_SIZE: usize = 5;
+
+const _: () = assert!(
+    settings::MAX_VALUES * ENTRY_SIZE <= journal::MAX_PAYLOAD,
+    "Settings do not fit in a stash record"
+);
+
+// The CONFIG region, which the journal spreads its records over. Reads go
+// through DMA so other futures run meanwhile.
+struct Region {
+    flash: Flash<'static, FLASH, Async, FLASH_SIZE>,
+}
+
+// Programming and erasing take flash out of XIP mode, so no code can run
+// from it until they finish whichever driver mode is used. Writes are a
+// single slot, and the sector a save moves on to is erased beforehand by
+// `Stash::prepare` where possible, so saves only stall on programming.
+impl journal::Storage for Region {
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), &'static str> {
+        self.flash
+            .read(CONFIG_OFFSET + offset, bytes)
+            .await
+            .map_err(|_| "Flash read failed")
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), &'static str> {
+        self.flash
+            .blocking_write(CONFIG_OFFSET + offset, bytes)
+            .map_err(|_| "Flash write failed")
+    }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub enum Hand {
-    Left,
-    Right,
+    async fn erase(&mut self, sector: u32) -> Result<(), &'static str> {
+        let from = CONFIG_OFFSET + sector * SECTOR_SIZE;
+        self.flash
+            .blocking_erase(from, from + SECTOR_SIZE)
+            .map_err(|_| "Flash erase failed")
+    }
 }
 
-#[derive(Clone)]
-pub struct Config {
-    pub hand: Hand,
+// Settings as stored in a record, one entry per value
+fn decode(payload: &[u8]) -> Result<Settings, &'static str> {
+    if !payload.len().is_multiple_of(ENTRY_SIZE) {
+        return Err("Invalid length");
+    }
+    let mut settings = Settings::default();
+    for entry in payload.chunks_exact(ENTRY_SIZE) {
+        let value = u32::from_le_bytes([entry[1], entry[2], entry[3], entry[4]]);
+        settings.set_raw(entry[0], value)?;
+    }
+    Ok(settings)
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self { hand: Hand::Left }
+// Returns the length of the payload written to `payload`
+fn encode(settings: &Settings, payload: &mut [u8; journal::MAX_PAYLOAD]) -> usize {
+    let mut len = 0;
+    for (id, value) in settings.iter_raw() {
+        payload[len] = id;
+        payload[len + 1..len + ENTRY_SIZE].copy_from_slice(&value.to_le_bytes());
+        len += ENTRY_SIZE;
     }
+    len
 }
 
 pub struct Stash {
-    flash: Flash<'static, FLASH, Blocking, FLASH_SIZE>,
+    journal: Journal<Region>,
+    // What is saved, which may differ from the running settings until the
+    // next reboot
+    settings: Settings,
 }
 
-#[repr(C)]
-struct RawConfig {
-    magic: u32,
-    hand: u32,
-    _reserved: [u32; 1022],
+pub enum LoadError {
+    // Flash is erased, so nothing was ever saved
+    Unconfigured,
+    Corrupt(&'static str),
 }
 
-impl TryFrom<RawConfig> for Config {
-    type Error = &'static str;
-    fn try_from(raw: RawConfig) -> Result<Self, Self::Error> {
-        if raw.magic != MAGIC {
-            return Err("Invalid magic");
+impl LoadError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoadError::Unconfigured => "Never configured",
+            LoadError::Corrupt(e) => e,
         }
-
-        let hand = match raw.hand {
-            0 => Hand::Left,
-            1 => Hand::Right,
-            _ => return Err("Invalid hand"),
-        };
-
-        Ok(Config { hand })
-    }
-}
-
-impl TryFrom<Config> for RawConfig {
-    type Error = &'static str;
-    fn try_from(config: Config) -> Result<Self, Self::Error> {
-        let hand = match config.hand {
-            Hand::Left => 0,
-            Hand::Right => 1,
-        };
-
-        Ok(RawConfig {
-            magic: MAGIC,
-            hand,
-            _reserved: [0; 1022],
-        })
     }
 }
 
-impl RawConfig {
-    const fn size() -> usize {
-        core::mem::size_of::<Self>()
-    }
+// Where successfully loaded settings came from
+pub enum Origin {
+    Current,
+    // Stored by the baseline firmware and upgraded in memory; saving it
+    // writes the current version
+    Legacy,
 }
 
 impl Stash {
-    pub fn new(flash: Peri<'static, FLASH>) -> Self {
+    pub fn new(flash: Peri<'static, FLASH>, dma: Peri<'static, DMA_CH0>) -> Self {
         Self {
-            flash: Flash::new_blocking(flash),
+            journal: Journal::new(Region {
+                flash: Flash::new(flash, dma),
+            }),
+            settings: Settings::default(),
         }
     }
 
-    pub fn load(&self) -> Result<Config, &'static str> {
-        let flash_ptr = (XIP_BASE + CONFIG_OFFSET) as *const RawConfig;
-        // SAFETY: CONFIG_OFFSET points to valid flash memory that is readable
-        // via XIP
-        let raw_config = unsafe { core::ptr::read_volatile(flash_ptr) };
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
 
-        Config::try_from(raw_config)
+    pub fn get<T: Value>(&self, key: Key<T>) -> T {
+        self.settings.get(key)
     }
 
-    pub fn save(&mut self, config: Config) -> Result<(), &'static str> {
-        let raw_config = RawConfig::try_from(config)?;
+    // Erase ahead for the next save, which otherwise erases when it fills a
+    // sector. Stalls for the erase, so only call it while nothing is typed.
+    pub async fn prepare(&mut self) -> Result<(), &'static str> {
+        self.journal.prepare().await
+    }
 
-        // SAFETY: RawConfig is repr(C) with known size and alignment
-        let config_bytes = unsafe {
-            core::slice::from_raw_parts(
-                &raw_config as *const RawConfig as *const u8,
-                RawConfig::size(),
-            )
-        };
+    // Write the settings to flash and report them
+    pub async fn save(&mut self) -> Result<(), &'static str> {
+        let mut payload = [0; journal::MAX_PAYLOAD];
+        let len = encode(&self.settings, &mut payload);
+        self.journal.append(VERSION, &payload[..len]).await?;
 
-        self.flash
-            .blocking_erase(CONFIG_OFFSET, CONFIG_OFFSET + RawConfig::size() as u32)
-            .map_err(|_| "Flash erase failed")?;
+        let _ = crate::SERIAL_CHANNEL.try_send("Settings saved\r\n");
+        self.settings.report();
+        Ok(())
+    }
 
-        self.flash
-            .blocking_write(CONFIG_OFFSET, config_bytes)
-            .map_err(|_| "Flash write failed")?;
+    // Change a setting by name, as typed on the console, and save it. The
+    // change is dropped if it cannot be saved.
+    pub async fn save_named(&mut self, name: &str, value: &str) -> Result<(), &'static str> {
+        let saved = self.settings.clone();
+        self.settings.set_named(name, value)?;
+        let result = self.save().await;
+        if result.is_err() {
+            self.settings = saved;
+        }
+        result
+    }
 
-        Ok(())
+    // On failure the settings are left at their defaults
+    pub async fn load(&mut self) -> Result<Origin, LoadError> {
+        let loaded = self.load_settings().await;
+        if loaded.is_err() {
+            self.settings = Settings::default();
+        }
+        loaded
+    }
+
+    async fn load_settings(&mut self) -> Result<Origin, LoadError> {
+        let Some(entry) = self.journal.load().await.map_err(LoadError::Corrupt)? else {
+            return self.load_legacy().await;
+        };
+
+        if entry.version != VERSION {
+            return Err(LoadError::Corrupt("Unsupported version"));
+        }
+        self.settings = decode(entry.payload()).map_err(LoadError::Corrupt)?;
+        Ok(Origin::Current)
+    }
+
+    // The legacy record is the magic and the hand, coded as the hand setting
+    // is, followed by reserved words
+    async fn load_legacy(&mut self) -> Result<Origin, LoadError> {
+        let mut bytes = [0; 8];
+        journal::Storage::read(self.journal.storage(), LEGACY_OFFSET, &mut bytes)
+            .await
+            .map_err(LoadError::Corrupt)?;
+        let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let hand = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+
+        match magic {
+            ERASED => return Err(LoadError::Unconfigured),
+            LEGACY_MAGIC => {}
+            _ => return Err(LoadError::Corrupt("Invalid magic")),
+        }
+        let mut settings = Settings::default();
+        settings
+            .set_raw(settings::HAND.id, hand)
+            .map_err(LoadError::Corrupt)?;
+        self.settings = settings;
+        Ok(Origin::Legacy)
     }
 }
@@ -1,12 +1,17 @@
+use crate::clock::Clock;
+use crate::link::{self, Received, SyncMessage};
+use crate::matrix::MatrixEvent;
+use crate::settings::Verbosity;
+use embassy_futures::select::{Either, select};
 use embassy_rp::Peri;
 use embassy_rp::gpio::{Input, Level, Output, Pull};
 use embassy_rp::peripherals::PIN_1;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
 use embassy_sync::channel::Channel;
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant};
 
-const BIT_DELAY_NS: u64 = 3000000; // 3ms per bit (~2.7kbps, 30ms per byte)
-const MAX_MESSAGE_LEN: usize = 2;
+const BIT_DELAY_NS: u64 = link::BIT_TIME.as_micros() * 1000;
+const HEX_DIGITS: &str = "0123456789ABCDEF";
 
 // Half-duplex split keyboard communication protocol:
 // - Single wire on PIN_1, idle high with pull-up
@@ -14,37 +19,12 @@ const MAX_MESSAGE_LEN: usize = 2;
 //   1. Sync pulse: low→high (receiver detects falling edge to resynchronize)
 //   2. 8 data bits, MSB first
 //   3. 1 even parity bit
-// - 6μs per bit (~137 kbps)
+// - link::BIT_TIME per bit
 // - Receiver samples at bit center after detecting sync pulse
+// - Messages are laid out in link.rs
 
-#[derive(Debug, Clone, Copy)]
-pub enum SyncMessage {
-    Test(u8),
-}
-
-impl SyncMessage {
-    fn msg_len(msg_type: u8) -> Option<usize> {
-        match msg_type {
-            1 => Some(1), // Test message: 1 byte (just the payload)
-            _ => None,
-        }
-    }
-
-    fn to_bytes(self) -> ([u8; MAX_MESSAGE_LEN], usize) {
-        match self {
-            SyncMessage::Test(val) => ([1, val], 2), // msg_type + payload
-        }
-    }
-
-    fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        match bytes.first()? {
-            1 => Some(SyncMessage::Test(*bytes.get(1)?)),
-            _ => None,
-        }
-    }
-}
-
-async fn receive_byte(pin: &mut Input<'_>) -> Result<u8, &'static str> {
+// Returns when a byte's sync pulse started
+async fn wait_for_sync(pin: &mut Input<'_>, clock: impl Clock) -> Instant {
     // Ensure we're in idle high state before looking for sync pulse
     while pin.is_low() {
         pin.wait_for_high().await;
@@ -52,17 +32,20 @@ async fn receive_byte(pin: &mut Input<'_>) -> Result<u8, &'static str> {
 
     // Wait for sync pulse falling edge
     pin.wait_for_low().await;
-    let t0 = embassy_time::Instant::now();
+    clock.now()
+}
 
+async fn receive_byte(
+    pin: &mut Input<'_>,
+    clock: impl Clock,
+    t0: Instant,
+) -> Result<u8, &'static str> {
     // Busy-wait for rising edge to get precise timing
     while pin.is_low() {}
 
     // Adaptive timing with async waits
     let target_ns = BIT_DELAY_NS * 2 + BIT_DELAY_NS / 2;
-    let elapsed_ns = embassy_time::Instant::now().duration_since(t0).as_micros() * 1000;
-    if target_ns > elapsed_ns {
-        Timer::after_nanos(target_ns - elapsed_ns).await;
-    }
+    clock.at(t0 + Duration::from_nanos(target_ns)).await;
 
     let mut byte = 0u8;
     let mut parity = 0u8;
@@ -76,18 +59,12 @@ async fn receive_byte(pin: &mut Input<'_>) -> Result<u8, &'static str> {
         // Adaptive async wait for next sample
         let next_sample_target_ns =
             BIT_DELAY_NS * 2 + BIT_DELAY_NS / 2 + BIT_DELAY_NS * (i as u64 + 1);
-        let elapsed_ns = embassy_time::Instant::now().duration_since(t0).as_micros() * 1000;
-        if next_sample_target_ns > elapsed_ns {
-            Timer::after_nanos(next_sample_target_ns - elapsed_ns).await;
-        }
+        clock.at(t0 + Duration::from_nanos(next_sample_target_ns)).await;
     }
 
     // Sample parity bit
     let next_sample_target_ns = BIT_DELAY_NS * 2 + BIT_DELAY_NS / 2 + BIT_DELAY_NS * 8;
-    let elapsed_ns = embassy_time::Instant::now().duration_since(t0).as_micros() * 1000;
-    if next_sample_target_ns > elapsed_ns {
-        Timer::after_nanos(next_sample_target_ns - elapsed_ns).await;
-    }
+    clock.at(t0 + Duration::from_nanos(next_sample_target_ns)).await;
     let parity_bit = if pin.is_high() { 1 } else { 0 };
 
     // Check parity
@@ -98,72 +75,82 @@ async fn receive_byte(pin: &mut Input<'_>) -> Result<u8, &'static str> {
     Ok(byte)
 }
 
-async fn read_sync_message(pin: &mut Input<'_>) -> Result<SyncMessage, &'static str> {
-    // Read message type byte
-    let msg_type = receive_byte(pin).await?;
-
-    // Log what we got
-    let _ = crate::SERIAL_CHANNEL.try_send("msg_type=0x");
-    let hex_hi = (msg_type >> 4) & 0xF;
-    let hex_lo = msg_type & 0xF;
-    let _ = crate::SERIAL_CHANNEL.try_send(match hex_hi {
-        0 => "0", 1 => "1", 2 => "2", 3 => "3", 4 => "4", 5 => "5", 6 => "6", 7 => "7",
-        8 => "8", 9 => "9", 10 => "A", 11 => "B", 12 => "C", 13 => "D", 14 => "E", 15 => "F",
-        _ => "?",
-    });
-    let _ = crate::SERIAL_CHANNEL.try_send(match hex_lo {
-        0 => "0", 1 => "1", 2 => "2", 3 => "3", 4 => "4", 5 => "5", 6 => "6", 7 => "7",
-        8 => "8", 9 => "9", 10 => "A", 11 => "B", 12 => "C", 13 => "D", 14 => "E", 15 => "F",
-        _ => "?",
-    });
-    let _ = crate::SERIAL_CHANNEL.try_send(" ");
+// Returns the message with when it started
+async fn read_sync_message(
+    pin: &mut Input<'_>,
+    clock: impl Clock,
+    rx_channel: &Channel<ThreadModeRawMutex, Received, 8>,
+    verbosity: Verbosity,
+) -> Result<(SyncMessage, Instant), &'static str> {
+    // Read message type byte, saying a message is on its way without waiting
+    // so no bit is sampled late
+    let started = wait_for_sync(pin, clock).await;
+    let _ = rx_channel.try_send(Received::Started);
+    let msg_type = receive_byte(pin, clock, started).await?;
+
+    if verbosity == Verbosity::Verbose {
+        let digit = |n: u8| &HEX_DIGITS[n as usize..n as usize + 1];
+        let _ = crate::SERIAL_CHANNEL.try_send("msg_type=0x");
+        let _ = crate::SERIAL_CHANNEL.try_send(digit(msg_type >> 4));
+        let _ = crate::SERIAL_CHANNEL.try_send(digit(msg_type & 0xF));
+        let _ = crate::SERIAL_CHANNEL.try_send("\r\n");
+    }
 
     // Determine how many more bytes to read
     let payload_len = SyncMessage::msg_len(msg_type).ok_or("unknown message type")?;
 
-    // Read payload bytes
-    let mut bytes = [0u8; MAX_MESSAGE_LEN];
+    // Read payload bytes. A sync pulse still missing once the whole message
+    // should have been sent means the secondary stopped part way, and the
+    // primary would otherwise hold its own keys for it.
+    let mut bytes = [0u8; link::MAX_MESSAGE_LEN];
     bytes[0] = msg_type;
+    let mut deadline = clock.at(started + link::MESSAGE_TIME);
     for i in 0..payload_len {
-        bytes[i + 1] = receive_byte(pin).await?;
+        let t0 = match select(wait_for_sync(pin, clock), &mut deadline).await {
+            Either::First(t0) => t0,
+            Either::Second(()) => return Err("message cut off"),
+        };
+        bytes[i + 1] = receive_byte(pin, clock, t0).await?;
     }
 
     // Decode message
-    SyncMessage::from_bytes(&bytes[..payload_len + 1]).ok_or("failed to decode message")
+    let msg =
+        SyncMessage::from_bytes(&bytes[..payload_len + 1]).ok_or("failed to decode message")?;
+    Ok((msg, started))
 }
 
 pub async fn primary(
     pin: Peri<'static, PIN_1>,
-    rx_channel: &'static Channel<ThreadModeRawMutex, SyncMessage, 8>,
+    clock: impl Clock,
+    rx_channel: &'static Channel<ThreadModeRawMutex, Received, 8>,
+    verbosity: Verbosity,
 ) {
     let mut pin = Input::new(pin, Pull::Up);
 
     loop {
-        let msg = match read_sync_message(&mut pin).await {
+        let (msg, started) = match read_sync_message(&mut pin, clock, rx_channel, verbosity).await {
             Ok(m) => m,
             Err(e) => {
                 let _ = crate::SERIAL_CHANNEL.try_send(e);
                 let _ = crate::SERIAL_CHANNEL.try_send("\r\n");
+                rx_channel.send(Received::Failed).await;
                 continue;
             }
         };
 
-        rx_channel.send(msg).await;
+        rx_channel.send(Received::Message(msg, started)).await;
     }
 }
 
-async fn send_byte(pin: &mut Output<'_>, byte: u8) {
-    let t0 = embassy_time::Instant::now();
+async fn send_byte(pin: &mut Output<'_>, byte: u8, clock: impl Clock) {
+    let t0 = clock.now();
 
     // Sync pulse with adaptive async timing
     pin.set_low();
-    Timer::after_nanos(BIT_DELAY_NS).await;
+    clock.at(t0 + Duration::from_nanos(BIT_DELAY_NS)).await;
     pin.set_high();
-    let elapsed_ns = embassy_time::Instant::now().duration_since(t0).as_micros() * 1000;
     let target_ns = BIT_DELAY_NS * 2;
-    if target_ns > elapsed_ns {
-        Timer::after_nanos(target_ns - elapsed_ns).await;
-    }
+    clock.at(t0 + Duration::from_nanos(target_ns)).await;
 
     // Send 8 data bits MSB first with adaptive async timing
     let mut parity = 0u8;
@@ -178,10 +165,7 @@ async fn send_byte(pin: &mut Output<'_>, byte: u8) {
 
         // Adaptive async wait for next bit
         let next_bit_target_ns = BIT_DELAY_NS * 2 + BIT_DELAY_NS * (i as u64 + 1);
-        let elapsed_ns = embassy_time::Instant::now().duration_since(t0).as_micros() * 1000;
-        if next_bit_target_ns > elapsed_ns {
-            Timer::after_nanos(next_bit_target_ns - elapsed_ns).await;
-        }
+        clock.at(t0 + Duration::from_nanos(next_bit_target_ns)).await;
     }
 
     // Send even parity bit
@@ -190,11 +174,8 @@ async fn send_byte(pin: &mut Output<'_>, byte: u8) {
     } else {
         pin.set_low();
     }
-    let next_bit_target_ns = BIT_DELAY_NS * 2 + BIT_DELAY_NS * 9;
-    let elapsed_ns = embassy_time::Instant::now().duration_since(t0).as_micros() * 1000;
-    if next_bit_target_ns > elapsed_ns {
-        Timer::after_nanos(next_bit_target_ns - elapsed_ns).await;
-    }
+    let next_bit_target_ns = BIT_DELAY_NS * link::BYTE_BITS;
+    clock.at(t0 + Duration::from_nanos(next_bit_target_ns)).await;
 
     // Return to idle high
     pin.set_high();
@@ -202,15 +183,17 @@ async fn send_byte(pin: &mut Output<'_>, byte: u8) {
 
 pub async fn secondary(
     pin: Peri<'static, PIN_1>,
-    tx_channel: &'static Channel<ThreadModeRawMutex, SyncMessage, 8>,
+    clock: impl Clock,
+    tx_channel: &'static Channel<ThreadModeRawMutex, MatrixEvent, 8>,
 ) {
     let mut pin = Output::new(pin, Level::High);
-    Timer::after_millis(1000).await;
+    clock.after(Duration::from_millis(1000)).await;
     loop {
-        let msg = tx_channel.receive().await;
-        let (bytes, len) = msg.to_bytes();
+        // Dated now, as the message starts, so time spent queued is counted
+        let event = tx_channel.receive().await;
+        let (bytes, len) = SyncMessage::new(event, clock.now()).to_bytes();
         for &byte in bytes.iter().take(len) {
-            send_byte(&mut pin, byte).await;
+            send_byte(&mut pin, byte, clock).await;
         }
     }
 }